@@ -3,8 +3,6 @@
 // serde = { version = "1.0", features = ["derive"] }
 // tokio = { version = "1", features = ["full"] }
 
-use std::collections::HashMap;
-use reqwest;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 // Structure pour désérialiser la réponse de l'API.
@@ -0,0 +1,217 @@
+// Bars built from the updates of the instruments: time bars of a fixed length aligned on the start
+// of the simulation, and tick or volume bars completing after a number of updates or of traded volume.
+// A bar specification is written like 1s, 1m, 5m or 1h for time bars, 100t for tick bars
+// and 5000v for volume bars. Bar files are CSV, one bar per line:
+//   start,end,open,high,low,close,volume,ticks
+//   60.0,120.0,45.97,46.02,45.91,45.99,57,57
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(into = "String", try_from = "String")]
+pub enum BarSpec {
+    Time(Duration), // completes at the end of each period
+    Ticks(usize),   // completes after this number of updates
+    Volume(u64),    // completes once this volume traded
+}
+
+impl FromStr for BarSpec {
+    type Err = String;
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let error = || format!("bars should be like '1s', '1m', '5m', '1h', '100t' or '5000v', but are '{}'", spec);
+        let split = spec.len().checked_sub(1).filter(|at| spec.is_char_boundary(*at)).ok_or_else(error)?;
+        let (count, unit) = spec.split_at(split);
+        let count = count.parse::<u64>().ok().filter(|count| *count > 0).ok_or_else(error)?;
+        let seconds = |unit: u64| count.checked_mul(unit).map(|secs| BarSpec::Time(Duration::from_secs(secs))).ok_or_else(error);
+        match unit {
+            "s" => seconds(1),
+            "m" => seconds(60),
+            "h" => seconds(3600),
+            "t" => usize::try_from(count).map(BarSpec::Ticks).map_err(|_| error()),
+            "v" => Ok(BarSpec::Volume(count)),
+            _ => Err(error())
+        }
+    }
+}
+
+impl fmt::Display for BarSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BarSpec::Time(length) => match length.as_secs() {
+                secs if secs % 3600 == 0 => write!(f, "{}h", secs / 3600),
+                secs if secs % 60 == 0 => write!(f, "{}m", secs / 60),
+                secs => write!(f, "{}s", secs),
+            },
+            BarSpec::Ticks(count) => write!(f, "{}t", count),
+            BarSpec::Volume(volume) => write!(f, "{}v", volume),
+        }
+    }
+}
+
+impl From<BarSpec> for String {
+    fn from(spec: BarSpec) -> String {
+        spec.to_string()
+    }
+}
+
+impl TryFrom<String> for BarSpec {
+    type Error = String;
+    fn try_from(spec: String) -> Result<Self, Self::Error> {
+        spec.parse()
+    }
+}
+
+// Prices of a bar, its times in seconds of simulation: the period of a time bar,
+// the first and last updates of a tick or volume bar.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Bar {
+    pub start: f64,
+    pub end: f64,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: u64,
+    pub ticks: usize,
+}
+
+impl Bar {
+    fn new(start: f64, end: f64, price: f64, volume: u64) -> Bar {
+        Bar { start, end, open: price, high: price, low: price, close: price, volume, ticks: 1 }
+    }
+
+    fn add(&mut self, price: f64, volume: u64) {
+        self.high = self.high.max(price);
+        self.low = self.low.min(price);
+        self.close = price;
+        self.volume += volume;
+        self.ticks += 1;
+    }
+}
+
+// Builder of the bars of one instrument, fed with its trades.
+#[derive(Debug)]
+pub struct Builder {
+    spec: BarSpec,
+    current: Option<Bar>,
+}
+
+impl Builder {
+    pub fn new(spec: BarSpec) -> Builder {
+        Builder { spec, current: None }
+    }
+
+    // Add a trade at `time`, returning the bar it completes. A time bar completes with the first trade
+    // of a later period, the periods without trades have no bar.
+    pub fn update(&mut self, time: f64, price: f64, volume: u64) -> Option<Bar> {
+        match self.spec {
+            BarSpec::Time(length) => {
+                let length = length.as_secs_f64();
+                let start = (time / length).floor() * length;
+                let completed = self.current.take_if(|bar| bar.start != start);
+                self.add(start, start + length, price, volume);
+                completed
+            }
+            BarSpec::Ticks(count) => {
+                let bar = self.add(time, time, price, volume);
+                bar.end = time;
+                if bar.ticks >= count { self.current.take() } else { None }
+            }
+            BarSpec::Volume(total) => {
+                let bar = self.add(time, time, price, volume);
+                bar.end = time;
+                if bar.volume >= total { self.current.take() } else { None }
+            }
+        }
+    }
+
+    // Add a trade to the bar in progress, or start a bar from `start` to `end` with it.
+    fn add(&mut self, start: f64, end: f64, price: f64, volume: u64) -> &mut Bar {
+        match &mut self.current {
+            Some(bar) => bar.add(price, volume),
+            None => self.current = Some(Bar::new(start, end, price, volume)),
+        }
+        self.current.as_mut().unwrap()
+    }
+
+    // Complete the bar in progress, if any.
+    pub fn finish(&mut self) -> Option<Bar> {
+        self.current.take()
+    }
+}
+
+// Writer of a bar file.
+pub struct BarFile {
+    writer: csv::Writer<File>,
+}
+
+impl BarFile {
+    pub fn create(path: &Path) -> Result<BarFile, String> {
+        let writer = csv::Writer::from_path(path).map_err(|e| format!("creating {}::{}", path.display(), e))?;
+        Ok(BarFile { writer })
+    }
+
+    pub fn write(&mut self, bar: &Bar) -> Result<(), String> {
+        self.writer.serialize(bar).map_err(|e| e.to_string())
+    }
+
+    pub fn flush(&mut self) -> Result<(), String> {
+        self.writer.flush().map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn specs_parse_and_display() {
+        assert_eq!("90s".parse::<BarSpec>(), Ok(BarSpec::Time(Duration::from_secs(90))));
+        assert_eq!("5m".parse::<BarSpec>(), Ok(BarSpec::Time(Duration::from_secs(300))));
+        assert_eq!("2h".parse::<BarSpec>(), Ok(BarSpec::Time(Duration::from_secs(7200))));
+        assert_eq!("100t".parse::<BarSpec>(), Ok(BarSpec::Ticks(100)));
+        assert_eq!("5000v".parse::<BarSpec>(), Ok(BarSpec::Volume(5000)));
+        for spec in ["60s", "120m", "90s", "100t", "5000v"] {
+            let display = spec.parse::<BarSpec>().unwrap().to_string();
+            assert_eq!(display.parse::<BarSpec>(), spec.parse::<BarSpec>());
+        }
+        assert_eq!("60s".parse::<BarSpec>().unwrap().to_string(), "1m");
+    }
+
+    #[test]
+    fn bad_specs_are_rejected() {
+        for spec in ["", "m", "0m", "0t", "-1s", "1.5m", "1d", "9999999999999999999h", "307445734561825861m", "1é"] {
+            assert!(spec.parse::<BarSpec>().is_err(), "{} should be rejected", spec);
+        }
+    }
+
+    #[test]
+    fn time_bars_complete_with_a_later_period() {
+        let mut builder = Builder::new(BarSpec::Time(Duration::from_secs(60)));
+        assert_eq!(builder.update(10.0, 5.0, 100), None);
+        assert_eq!(builder.update(30.0, 7.0, 200), None);
+        assert_eq!(builder.update(50.0, 4.0, 100), None);
+        let bar = builder.update(130.0, 6.0, 100).unwrap();
+        assert_eq!(bar, Bar { start: 0.0, end: 60.0, open: 5.0, high: 7.0, low: 4.0, close: 4.0, volume: 400, ticks: 3 });
+        let bar = builder.finish().unwrap();
+        assert_eq!((bar.start, bar.end, bar.open, bar.ticks), (120.0, 180.0, 6.0, 1));
+        assert_eq!(builder.finish(), None);
+    }
+
+    #[test]
+    fn tick_and_volume_bars_complete_on_their_count() {
+        let mut ticks = Builder::new(BarSpec::Ticks(2));
+        assert_eq!(ticks.update(1.0, 5.0, 100), None);
+        let bar = ticks.update(2.0, 6.0, 100).unwrap();
+        assert_eq!((bar.start, bar.end, bar.high, bar.ticks), (1.0, 2.0, 6.0, 2));
+
+        let mut volume = Builder::new(BarSpec::Volume(250));
+        assert_eq!(volume.update(1.0, 5.0, 100), None);
+        assert_eq!(volume.update(2.0, 5.0, 100), None);
+        assert_eq!(volume.update(3.0, 5.0, 100).map(|bar| bar.volume), Some(300));
+    }
+}
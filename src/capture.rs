@@ -0,0 +1,109 @@
+// Capture files of a data feed: one JSON line per image, update or status change of an
+// instrument, stamped with the seconds of simulation time since the clock started:
+//   {"time":0.0,"type":"image","symbol":"AAPL","quote":{"last":45.97,...},"depth":{"bids":[...],"asks":[...]}}
+//   {"time":0.153,"type":"update","symbol":"AAPL","quote":{"last":45.98,...},"depth":[{"side":"bid",...}]}
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Clock;
+use crate::instrument::Event;
+
+// An event of the feed and when it happened.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Record {
+    pub time: f64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+// A record written from a borrowed event.
+#[derive(Serialize)]
+struct RecordRef<'a> {
+    time: f64,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+// Writer of a capture file, buffered until it is flushed or dropped.
+pub struct Recorder {
+    writer: BufWriter<File>,
+    clock: Arc<Clock>,
+}
+
+impl Recorder {
+    pub fn create(path: &str, clock: Arc<Clock>) -> Result<Recorder, String> {
+        let file = File::create(path).map_err(|e| format!("creating {}::{}", path, e))?;
+        Ok(Recorder { writer: BufWriter::new(file), clock })
+    }
+
+    // Write an event stamped with the current time of the clock.
+    pub fn write(&mut self, event: &Event) -> io::Result<()> {
+        let record = RecordRef { time: self.clock.now().as_secs_f64(), event };
+        serde_json::to_writer(&mut self.writer, &record)?;
+        self.writer.write_all(b"\n")
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Read the records of a capture file in order, a malformed line is an error giving its number.
+pub fn read(path: &str) -> Result<impl Iterator<Item = Result<Record, String>>, String> {
+    let file = File::open(path).map_err(|e| format!("reading {}::{}", path, e))?;
+    let path = path.to_string();
+    Ok(BufReader::new(file).lines().enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(move |(n, line)| {
+            let line = line.map_err(|e| format!("reading {}::{}", path, e))?;
+            serde_json::from_str(&line).map_err(|e| format!("{} line {}::{}", path, n + 1, e))
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+    use crate::clock::Mode;
+    use crate::instrument::Status;
+    use crate::protocol::{Depth, Quote};
+
+    fn image(symbol: &str, last: f64) -> Event {
+        let quote = Quote { last, ..Quote::default() };
+        Event::Image { symbol: symbol.to_string(), quote, depth: Depth::default(), indicators: Default::default() }
+    }
+
+    #[test]
+    fn records_read_back_in_order_with_their_time() {
+        let path = std::env::temp_dir().join(format!("capture-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        let clock = Arc::new(Clock::new(Mode::Virtual));
+        let events = vec![
+            image("AAPL", 45.97),
+            Event::Status { symbol: "AAPL".to_string(), status: Status::Halted },
+            image("MSFT", 123.67),
+        ];
+        let mut recorder = Recorder::create(path, Arc::clone(&clock)).unwrap();
+        for (n, event) in events.iter().enumerate() {
+            clock.sleep_until(Duration::from_millis(250 * n as u64));
+            recorder.write(event).unwrap();
+        }
+        recorder.flush().unwrap();
+
+        let records: Vec<Record> = read(path).unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(records.iter().map(|r| r.time).collect::<Vec<_>>(), vec![0.0, 0.25, 0.5]);
+        assert_eq!(records.into_iter().map(|r| r.event).collect::<Vec<_>>(), events);
+
+        // a malformed line is reported with its number, the next ones are still read
+        let contents = fs::read_to_string(path).unwrap();
+        fs::write(path, contents.replacen('\n', "\n\n{\"time\":\n", 1)).unwrap();
+        let records: Vec<Result<Record, String>> = read(path).unwrap().collect();
+        fs::remove_file(path).unwrap();
+        assert_eq!(records.len(), 4);
+        assert!(records[1].as_ref().unwrap_err().contains("line 3"));
+        assert!(records[3].is_ok());
+    }
+}
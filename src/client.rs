@@ -1,38 +1,46 @@
-use std::thread;
+use std::io;
+use std::io::BufReader;
 use std::net::TcpStream;
-use std::net::SocketAddr;
-use std::io::Write;
-use std::io::Read;
+use std::thread;
+use std::time::Duration;
+
+use crate::protocol::{self, Message};
 
-pub fn request<'a>(host: &'a str, port: u16, what: &'a str) {
-    match TcpStream::connect(format!("{}:{}", host, port)) {
+// Number of times a refused connection is retried.
+const CONNECT_ATTEMPTS: usize = 20;
+
+// A market data connection to the exchange simulator.
+pub struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
 
-        Ok(mut stream) => {
-            println!("connected stream::{:?}", stream);
-            stream.write(what.as_bytes()).unwrap();
-            stream.flush().unwrap();
-            let mut buffer = [0; 1024];
-            println!("handle connection");
-            for i in 0..5 {
-                println!("::::::::Reading for {}", what);
-                match stream.read(&mut buffer) {
-                    Err(e) => {
-                        eprintln!("Error reading from exchange {}", e);
-                        break;
-                    }
-                    Ok(n) => {
-                        if n < 1 {
-                            println!("connectopn closed");
-                            break;
-                        }
-                        let mut sb = std::str::from_utf8(&buffer).unwrap();
-                        println!("from exchange({}) <- {}", what, sb);
-                    }
+impl Connection {
+    // Connect to the exchange, retrying for a while as it may still be starting.
+    pub fn connect(host: &str, port: u16) -> io::Result<Connection> {
+        let mut attempt = 0;
+        let writer = loop {
+            match TcpStream::connect(format!("{}:{}", host, port)) {
+                Ok(stream) => break stream,
+                Err(e) if attempt < CONNECT_ATTEMPTS && e.kind() == io::ErrorKind::ConnectionRefused => {
+                    attempt += 1;
+                    thread::sleep(Duration::from_millis(100));
                 }
+                Err(e) => return Err(e)
             }
-        }
-        Err(e) => {
-            println!("client Error::{}", e);
-        }
+        };
+        println!("connected stream::{:?}", writer);
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Connection { reader, writer })
+    }
+
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        protocol::write(&mut self.writer, message)
+    }
+
+    // Next message from the exchange, `None` once the exchange closed the connection.
+    pub fn receive(&mut self) -> io::Result<Option<Message>> {
+        protocol::read(&mut self.reader)
     }
 }
+
@@ -0,0 +1,122 @@
+// Simulation clock shared by the data feed and the exchange simulator.
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How simulated time relates to wall clock time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Real,         // simulated time is wall clock time
+    Scaled(f64),  // simulated time runs `n` times faster than wall clock time
+    Virtual       // no waiting at all, time jumps to the next event
+}
+
+// Range of the speeds of a scaled clock, keeping its durations within those of the standard library.
+const MIN_SPEED: f64 = 1e-3;
+const MAX_SPEED: f64 = 1e6;
+
+impl FromStr for Mode {
+    type Err = String;
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "real" => Ok(Mode::Real),
+            "virtual" | "fast" => Ok(Mode::Virtual),
+            _ => match mode.strip_suffix('x').map(f64::from_str) {
+                Some(Ok(speed)) if (MIN_SPEED..=MAX_SPEED).contains(&speed) => Ok(Mode::Scaled(speed)),
+                _ => Err(format!("clock should be 'real', 'virtual' or a speed from {}x to {}x like '100x', but is '{}'", MIN_SPEED, MAX_SPEED, mode))
+            }
+        }
+    }
+}
+
+pub struct Clock {
+    mode: Mode,
+    begin: Instant,
+    now: Mutex<Duration> // current time of a virtual clock
+}
+
+impl Clock {
+    pub fn new(mode: Mode) -> Clock {
+        Clock {
+            mode,
+            begin: Instant::now(),
+            now: Mutex::new(Duration::ZERO)
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    // Simulated time elapsed since the clock was created.
+    pub fn now(&self) -> Duration {
+        match self.mode {
+            Mode::Real => self.begin.elapsed(),
+            Mode::Scaled(speed) => self.begin.elapsed().mul_f64(speed),
+            Mode::Virtual => *self.now.lock().unwrap()
+        }
+    }
+
+    // Block until the simulated time reaches `at`.
+    pub fn sleep_until(&self, at: Duration) {
+        match self.mode {
+            Mode::Real | Mode::Scaled(_) => {
+                let now = self.now();
+                if at > now {
+                    let wait = at - now;
+                    thread::sleep(match self.mode {
+                        Mode::Scaled(speed) => wait.div_f64(speed),
+                        _ => wait
+                    });
+                }
+            }
+            Mode::Virtual => {
+                let mut now = self.now.lock().unwrap();
+                if at > *now {
+                    *now = at;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes_parse() {
+        assert_eq!("real".parse::<Mode>(), Ok(Mode::Real));
+        assert_eq!("virtual".parse::<Mode>(), Ok(Mode::Virtual));
+        assert_eq!("fast".parse::<Mode>(), Ok(Mode::Virtual));
+        assert_eq!("100x".parse::<Mode>(), Ok(Mode::Scaled(100.0)));
+        assert_eq!("0.5x".parse::<Mode>(), Ok(Mode::Scaled(0.5)));
+        assert_eq!("1e6x".parse::<Mode>(), Ok(Mode::Scaled(1e6)));
+    }
+
+    #[test]
+    fn bad_modes_are_rejected() {
+        for mode in ["", "100", "x", "0x", "-2x", "infx", "NaNx", "1e300x", "1e-300x", "2e6x", "slow"] {
+            assert!(mode.parse::<Mode>().is_err(), "{} should be rejected", mode);
+        }
+    }
+
+    #[test]
+    fn virtual_time_jumps_forward_only() {
+        let clock = Clock::new(Mode::Virtual);
+        assert_eq!(clock.now(), Duration::ZERO);
+        clock.sleep_until(Duration::from_secs(3600));
+        assert_eq!(clock.now(), Duration::from_secs(3600));
+        clock.sleep_until(Duration::from_secs(60));
+        assert_eq!(clock.now(), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn scaled_time_runs_faster() {
+        let clock = Clock::new(Mode::Scaled(1000.0));
+        clock.sleep_until(Duration::from_secs(2));
+        assert!(clock.now() >= Duration::from_secs(2));
+        assert!(clock.begin.elapsed() < Duration::from_secs(1));
+    }
+}
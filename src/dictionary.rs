@@ -0,0 +1,186 @@
+// Dictionary of the instruments: their kind and static reference data, loaded from a JSON file
+// shared by the data feed and the exchange simulator, with the chain records of the feed
+// and the trading sessions of the kinds not following the default ones (see session.rs):
+//   {"symbols": {"AAPL": {"name": "Apple", "kind": "Equity", "close": 45.97, "volatility": 0.02}},
+//    "chains": {"0#TECH": ["AAPL", "MSFT"]},
+//    "synthetics": {"EURCHF=": {"name": "Euro / Swiss Franc", "kind": "Currency", "expression": "EUR= * CHF="}}}
+// A synthetic instrument is priced from the instruments of its expression (see synthetic.rs).
+// The end of day snapshot of a run is a dictionary too, each symbol closing at its last price
+// with the final prices of the day, so the next run starts from there:
+//   "AAPL": {"name": "Apple", "kind": "Equity", "close": 46.2, "volatility": 0.02,
+//            "snapshot": {"last": 46.2, "bid": 46.17, "ask": 46.23, "open": 45.97, "close": 45.97, "tick": 42}}
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+
+use crate::instrument::{Instrument, Kind};
+use crate::protocol::Quote;
+use crate::session::Calendar;
+use crate::synthetic::Expr;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Dictionary {
+    pub symbols: BTreeMap<String, DailyData>,
+    // constituents of each chain, in order
+    #[serde(default)]
+    pub chains: BTreeMap<String, Vec<String>>,
+    // calendar of each kind name
+    #[serde(default)]
+    pub sessions: HashMap<String, Calendar>,
+    #[serde(default)]
+    pub synthetics: BTreeMap<String, SyntheticData>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyntheticData {
+    pub name : String,
+    pub kind : String,
+    pub expression : String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DailyData {
+    pub name : String,
+    pub kind : String,
+    pub close : f64,
+    #[serde(default = "default_volatility")]
+    pub volatility : f64,
+    // price process: "gbm", "ou" or "jump", defaults on the kind
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model : Option<String>,
+    // prices at the end of the day written in a snapshot, informative only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot : Option<Quote>,
+}
+
+fn default_volatility() -> f64 {
+    0.04
+}
+
+pub fn load(path: &str) -> Result<Dictionary, String> {
+    println!(">>>>>>>>>in load");
+    let contents = fs::read_to_string(path).map_err(|e| format!("reading {}::{}", path, e))?;
+    let dictionary = serde_json::from_str::<Dictionary>(&contents).map_err(|e| format!("JSON {}::{}", path, e));
+    println!("<<<<<<<<<out load");
+    dictionary
+}
+
+// Write a dictionary to a JSON file, like the end of day snapshot of a run.
+pub fn save(dictionary: &Dictionary, path: &str) -> Result<(), String> {
+    let contents = serde_json::to_string_pretty(dictionary).map_err(|e| format!("JSON {}::{}", path, e))?;
+    fs::write(path, contents).map_err(|e| format!("writing {}::{}", path, e))
+}
+
+impl Dictionary {
+    // Instruments of the dictionary ordered by symbol, seeded with their reference data.
+    // An entry with an unknown kind or model is reported and left out.
+    pub fn instruments(&self) -> Vec<Instrument> {
+        self.symbols.iter()
+            .filter_map(|(symbol, daily)| {
+                let instrument = Kind::parse(&daily.kind, symbol.to_string())
+                    .map(|kind| Instrument::new(kind).with_description(&daily.name))
+                    .and_then(|i| {
+                        i.set_model(daily.close, daily.volatility, daily.model.as_deref())
+                            .map(|_| i)
+                            .map_err(|e| format!("{} for {}", e, symbol))
+                    });
+                match instrument {
+                    Ok(instrument) => Some(instrument),
+                    Err(e) => {
+                        println!("ERROR::{}", e);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    // Synthetic instruments of the dictionary ordered by symbol, with their expression.
+    // An entry with an unknown kind or a bad expression is reported and left out.
+    pub fn synthetics(&self) -> Vec<(Instrument, Expr)> {
+        self.synthetics.iter()
+            .filter_map(|(symbol, synthetic)| {
+                let parsed = Kind::parse(&synthetic.kind, symbol.to_string())
+                    .and_then(|kind| synthetic.expression.parse::<Expr>().map(|expression| (kind, expression)))
+                    .map_err(|e| format!("{} for {}", e, symbol));
+                match parsed {
+                    Ok((kind, expression)) => Some((Instrument::new(kind).with_description(&synthetic.name), expression)),
+                    Err(e) => {
+                        println!("ERROR::{}", e);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    // Take the end of day snapshot of the instruments: each one closes at its last price when it traded,
+    // at its previous close otherwise, keeping the reference data of its entry.
+    // Symbols of the dictionary no longer listed are left as they were, and synthetic instruments
+    // are priced again from their constituents.
+    pub fn end_of_day(&mut self, instruments: &[Arc<Instrument>]) {
+        for i in instruments.iter().filter(|i| !self.synthetics.contains_key(i.get_name())) {
+            let quote = i.quote();
+            let close = if quote.tick > 0 { quote.last } else { quote.close };
+            let daily = self.symbols.entry(i.get_name().to_string()).or_insert_with(|| DailyData {
+                name: i.get_description().to_string(),
+                kind: i.get_kind().name().to_string(),
+                close,
+                volatility: default_volatility(),
+                model: None,
+                snapshot: None,
+            });
+            daily.close = close;
+            daily.snapshot = Some(quote);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dictionary() -> Dictionary {
+        serde_json::from_str(r#"{
+            "symbols": {"MSFT": {"name": "Microsoft", "kind": "Equity", "close": 123.67},
+                        "AAPL": {"name": "Apple", "kind": "Equity", "close": 45.97, "volatility": 0.02},
+                        "EUR=": {"name": "Euro", "kind": "Currency", "close": 1.0852},
+                        "CHF=": {"name": "Swiss Franc", "kind": "Currency", "close": 0.8817},
+                        "BAD": {"name": "Bad", "kind": "Unknown", "close": 1.0}},
+            "synthetics": {"EURCHF=": {"name": "Euro / Swiss Franc", "kind": "Currency", "expression": "EUR= * CHF="}}
+        }"#).unwrap()
+    }
+
+    fn traded(instrument: Instrument, last: f64) -> Arc<Instrument> {
+        let quote = instrument.quote();
+        instrument.apply(&Quote { last, tick: quote.tick + 1, ..quote });
+        Arc::new(instrument)
+    }
+
+    #[test]
+    fn instruments_are_ordered_by_symbol() {
+        let names: Vec<String> = dictionary().instruments().iter().map(|i| i.get_name().to_string()).collect();
+        assert_eq!(names, vec!["AAPL", "CHF=", "EUR=", "MSFT"]);
+    }
+
+    #[test]
+    fn end_of_day_closes_at_the_last_price() {
+        let mut dictionary = dictionary();
+        let mut instruments: Vec<Arc<Instrument>> = dictionary.instruments().into_iter().map(Arc::new).collect();
+        instruments[0] = traded(Instrument::new(Kind::Equity("AAPL".to_string())).with_description("Apple"), 46.2);
+        instruments.push(traded(Instrument::new(Kind::Equity("IBM".to_string())).with_description("IBM"), 180.5));
+        instruments.push(traded(Instrument::new(Kind::Currency("EURCHF=".to_string())), 0.96));
+        dictionary.end_of_day(&instruments);
+
+        let aapl = &dictionary.symbols["AAPL"];
+        assert_eq!((aapl.close, aapl.volatility), (46.2, 0.02));
+        assert_eq!(aapl.snapshot.map(|s| s.last), Some(46.2));
+        // not traded, it closes where it opened
+        assert_eq!(dictionary.symbols["MSFT"].close, 123.67);
+        let ibm = &dictionary.symbols["IBM"];
+        assert_eq!((ibm.name.as_str(), ibm.kind.as_str(), ibm.close), ("IBM", "Equity", 180.5));
+        assert_eq!(dictionary.symbols["BAD"].close, 1.0);
+        assert!(!dictionary.symbols.contains_key("EURCHF="));
+    }
+}
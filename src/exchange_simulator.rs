@@ -1,96 +1,673 @@
-use std::fs;
-use std::io::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, ErrorKind};
 use std::net::TcpListener;
 use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::time::Duration;
-use serde::{Deserialize, Serialize};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand_distr::{Distribution, Exp};
 
-#[path="threadpool.rs"] mod threadpool;
-use threadpool::ThreadPool;
+#[path="order_book.rs"] mod order_book;
+use order_book::{BookState, Fill, Level, OrderBook, OrderId, Owner, Report, Side, Status};
+#[path="order_entry.rs"] mod order_entry;
+use order_entry::{OrderMessage, Trader};
+#[path="fix.rs"] mod fix;
+use crate::clock::{Clock, Mode};
+use crate::dictionary::Dictionary;
+use crate::instrument::Kind;
+use crate::price_model::{self, PriceProcess};
+use crate::protocol::{self, Condition, DepthChange, Message, Quote};
+use crate::scheduler::{self, Scheduler};
+use crate::session::{Phase, Sessions};
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Dictionary {
-    symbols: std::collections::HashMap<String, DailyData>,
+// A symbol listed on the exchange with the process generating its prices.
+// Its book is seeded with the orders of a simulated market maker quoting around the
+// process price, and simulated takers hitting those quotes produce the trades.
+// During the auctions the market maker stands for the crowd, with a bid and an ask
+// crossing around the process price.
+struct Listing {
+    kind: Kind,
+    process: Box<dyn PriceProcess>,
+    rng: StdRng,
+    quote: Quote,
+    book: OrderBook,
+    maker_bid: Option<OrderId>,
+    maker_ask: Option<OrderId>,
+    changes: Vec<Level>, // levels of the book changed since the last update
+    phase: Phase, // trading phase at the last event of the symbol
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct DailyData {
-    name : String,
-    kind : String,
-    close : f32,
-    #[serde(default = "default_volatility")]
-    volatility : f32,
+// Owner of the simulated market maker orders.
+const MARKET_MAKER: Owner = usize::MAX;
+// Probability for a tick to bring a simulated market order.
+const TAKER_PROBABILITY: f64 = 0.3;
+
+impl Listing {
+    // Quote both sides around `mid`, replacing the previous quotes of the market maker.
+    // Returns the trades made with crossing orders of the clients.
+    fn requote(&mut self, mid: f64) -> Vec<Fill> {
+        let spread = mid * price_model::half_spread(&self.kind);
+        let tick = price_model::tick_size(&self.kind);
+        let bid = self.book.round(mid - spread);
+        let ask = self.book.round(mid + spread).max(bid + tick);
+        let size = self.rng.gen_range(1..=10) * 100;
+        let mut fills = Vec::new();
+        self.maker_bid = self.quote_side(self.maker_bid, Side::Buy, bid, size, &mut fills);
+        self.maker_ask = self.quote_side(self.maker_ask, Side::Sell, ask, size, &mut fills);
+        fills
+    }
+
+    // Move a quote of the market maker, or enter a new one once the previous was filled.
+    // Returns the order still resting in the book.
+    fn quote_side(&mut self, order: Option<OrderId>, side: Side, price: f64, size: u64, fills: &mut Vec<Fill>) -> Option<OrderId> {
+        let report = match order {
+            Some(id) => self.book.replace(id, price, size).or_else(|_| {
+                // the quote was filled meanwhile, drop what may remain of it
+                let _ = self.book.cancel(id);
+                self.book.limit(MARKET_MAKER, side, price, size)
+            }),
+            None => self.book.limit(MARKET_MAKER, side, price, size)
+        };
+        let report = report.ok()?;
+        self.changes.extend(report.book);
+        self.trade(&report.fills);
+        fills.extend(report.fills);
+        Some(report.order).filter(|_| report.leaves > 0)
+    }
+
+    // Maybe send a simulated market order against the book, returning its trades.
+    fn take(&mut self) -> Vec<Fill> {
+        if self.rng.gen_bool(TAKER_PROBABILITY) {
+            let side = if self.rng.gen_bool(0.5) { Side::Buy } else { Side::Sell };
+            let size = self.rng.gen_range(1..=5) * 100;
+            if let Ok(report) = self.book.market(side, size) {
+                self.changes.extend(report.book);
+                self.trade(&report.fills);
+                return report.fills;
+            }
+        }
+        Vec::new()
+    }
+
+    // Enter the orders of the crowd for the auction: a bid above `mid` and an ask below it,
+    // of different sizes, so that the auction prints near `mid` unless the clients move it.
+    fn auction_quotes(&mut self, mid: f64) {
+        let spread = mid * price_model::half_spread(&self.kind);
+        let tick = price_model::tick_size(&self.kind);
+        let ask = self.book.round(mid - spread);
+        let bid = self.book.round(mid + spread).max(ask + tick);
+        let (bid_size, ask_size) = (self.rng.gen_range(1..=10) * 100, self.rng.gen_range(1..=10) * 100);
+        let mut fills = Vec::new();
+        self.maker_bid = self.quote_side(self.maker_bid, Side::Buy, bid, bid_size, &mut fills);
+        self.maker_ask = self.quote_side(self.maker_ask, Side::Sell, ask, ask_size, &mut fills);
+    }
+
+    // Withdraw the orders of the market maker.
+    fn withdraw(&mut self) {
+        for order in [self.maker_bid.take(), self.maker_ask.take()].into_iter().flatten() {
+            if let Ok(report) = self.book.cancel(order) {
+                self.changes.extend(report.book);
+            }
+        }
+    }
+
+    // Set the book for a phase entered without an auction print: an auction starts with
+    // the crowd around the last price, a closed book only accepts cancellations.
+    fn enter(&mut self, phase: Phase) {
+        match phase {
+            Phase::Continuous => self.book.set_state(BookState::Continuous),
+            Phase::Closed => {
+                self.withdraw();
+                self.book.set_state(BookState::Closed);
+            }
+            _ if self.book.state() != BookState::Auction => {
+                self.book.set_state(BookState::Auction);
+                self.auction_quotes(self.quote.last);
+            }
+            _ => {}
+        }
+    }
+
+    // Uncross an auction once the crowd moved around `mid`, then set the book for the phase
+    // that follows. The auction price becomes the open of the session or its close, `mid`
+    // rounded to the tick when nothing crossed. Returns the trades of the uncrossing.
+    fn uncross(&mut self, auction: Phase, phase: Phase, mid: f64) -> Vec<Fill> {
+        // an auction of no length starts and uncrosses at once
+        self.book.set_state(BookState::Auction);
+        self.auction_quotes(mid);
+        let (price, fills) = match self.book.uncross() {
+            Some(uncross) => {
+                self.changes.extend(uncross.book);
+                // the fills of both sides trade the same volume
+                let volume = uncross.fills.iter().map(|f| f.quantity).sum::<u64>() / 2;
+                self.quote.prints.trade(uncross.price, volume, Condition::of(Some(auction)));
+                (uncross.price, uncross.fills)
+            }
+            None => (self.book.round(mid), Vec::new())
+        };
+        match auction {
+            Phase::OpeningAuction => self.quote.open = price,
+            Phase::ClosingAuction => self.quote.close = price,
+            _ => {}
+        }
+        self.enter(phase);
+        fills
+    }
+
+    // State of the auction in the current phase, with its indicative price and volume.
+    fn indicative(&self, symbol: &str) -> Message {
+        let (price, volume) = self.book.indicative().map_or((None, 0), |(price, volume)| (Some(price), volume));
+        Message::Indicative { symbol: symbol.to_string(), phase: self.phase, price, volume }
+    }
+
+    // Add the regular trades of `fills` to the prints of the quote.
+    fn trade(&mut self, fills: &[Fill]) {
+        for fill in fills {
+            self.quote.prints.trade(fill.price, fill.quantity, Condition::Regular);
+        }
+    }
+
+    // Refresh the published quote from the book.
+    fn refresh(&mut self) {
+        let quote = &mut self.quote;
+        if let Some(last) = self.book.last() {
+            if quote.open == 0.0 {
+                quote.open = last;
+            }
+            quote.last = last;
+        }
+        if let Some((bid, _)) = self.book.best_bid() {
+            quote.bid = bid;
+        }
+        if let Some((ask, _)) = self.book.best_ask() {
+            quote.ask = ask;
+        }
+    }
+
+    // The update of `symbol` after an order, followed by the state of the auction during one.
+    fn order_updates(&mut self, symbol: &str) -> Vec<Message> {
+        let mut messages = vec![self.update(symbol)];
+        if self.book.state() == BookState::Auction {
+            messages.push(self.indicative(symbol));
+        }
+        messages
+    }
+
+    // Refresh the quote and build the update of `symbol` with the levels changed since the last one.
+    fn update(&mut self, symbol: &str) -> Message {
+        self.refresh();
+        self.quote.tick += 1;
+        // only the last state of a level changed several times is sent
+        let mut depth: Vec<DepthChange> = Vec::new();
+        for change in self.changes.drain(..).rev().map(DepthChange::from) {
+            if !depth.iter().any(|c| c.side == change.side && c.price == change.price) {
+                depth.push(change);
+            }
+        }
+        depth.reverse();
+        Message::Update { symbol: symbol.to_string(), quote: self.quote, depth }
+    }
 }
 
-fn default_volatility() -> f32 {
-    0.04
+// A connected market data client and the symbols it subscribed to.
+struct Session {
+    sender: SyncSender<Message>,
+    symbols: HashSet<String>,
 }
 
-fn load(path: &str) {
-    println!(">>>>>>>>>in load");
-    let contents = fs::read_to_string(path).unwrap();
-    match serde_json::from_str::<Dictionary>(&contents) {
-        Ok(dictionary) => {
-            println!("dictionary::{:#?}", dictionary);
-            for(k, s) in dictionary.symbols {
-                println!("{} -> {:#?}", k, s);
+// An order of an order entry session resting in a book, to report its trades.
+struct RestingOrder {
+    trader: Trader,
+    client_order_id: String,
+    side: Side,
+}
+
+// Prices of the listed symbols, the sessions streaming them and the orders resting in the books.
+pub struct Market {
+    listings: Mutex<BTreeMap<String, Listing>>,
+    sessions: Mutex<HashMap<usize, Session>>,
+    subscribed: Condvar, // signaled when a symbol gets its first subscriber
+    next_session: AtomicUsize,
+    resting: Mutex<HashMap<(String, OrderId), RestingOrder>>,
+    trading_sessions: Option<Arc<Sessions>>, // trading is continuous without sessions
+    backpressure: bool, // wait for slow sessions instead of disconnecting them
+}
+
+// Number of messages queued for a client before the market waits for it.
+const SESSION_QUEUE: usize = 1024;
+// Idle time after which a heartbeat is sent to a client.
+const HEARTBEAT: Duration = Duration::from_secs(1);
+
+impl Market {
+    fn new(dictionary: Dictionary, seed: u64, trading_sessions: Option<Arc<Sessions>>, backpressure: bool) -> Market {
+        let mut listings = BTreeMap::new();
+        for (symbol, daily) in dictionary.symbols {
+            let kind = match Kind::parse(&daily.kind, symbol.to_string()) {
+                Ok(kind) => kind,
+                Err(e) => {
+                    eprintln!("ERROR::{}", e);
+                    continue;
+                }
+            };
+            let model = daily.model.as_deref().unwrap_or(price_model::default_model(&kind));
+            let process = match price_model::by_name(model, daily.close, daily.volatility) {
+                Ok(process) => process,
+                Err(e) => {
+                    eprintln!("ERROR::{} for {}", e, symbol);
+                    continue;
+                }
+            };
+            let quote = Quote {
+                last: daily.close,
+                close: daily.close,
+                ..Quote::default()
+            };
+            let rng = StdRng::seed_from_u64(seed ^ scheduler::stable_hash(&symbol));
+            let book = OrderBook::new(price_model::tick_size(&kind));
+            let mut listing = Listing { kind, process, rng, quote, book, maker_bid: None, maker_ask: None, changes: Vec::new(), phase: Phase::Continuous };
+            listing.requote(daily.close);
+            listing.refresh();
+            listing.changes.clear();
+            listings.insert(symbol, listing);
+        }
+        Market {
+            listings: Mutex::new(listings),
+            sessions: Mutex::new(HashMap::new()),
+            subscribed: Condvar::new(),
+            next_session: AtomicUsize::new(0),
+            resting: Mutex::new(HashMap::new()),
+            trading_sessions,
+            backpressure,
+        }
+    }
+
+    // Trading phase of a kind of instrument after `at` of simulation.
+    fn phase(&self, kind: &Kind, at: Duration) -> Phase {
+        self.trading_sessions.as_ref().map_or(Phase::Continuous, |s| s.phase(kind, at))
+    }
+
+    // Time of the next event of a symbol otherwise due at `next`, `None` when it does not trade again.
+    fn next_event(&self, kind: &Kind, at: Duration, next: Duration) -> Option<Duration> {
+        self.trading_sessions.as_ref().map_or(Some(next), |s| s.next_event(kind, at, next))
+    }
+
+    // A new owner id for the orders of an order entry session.
+    fn new_owner(&self) -> Owner {
+        self.next_session.fetch_add(1, Ordering::SeqCst)
+    }
+
+    // Run an order of `trader` on the book of `symbol` and queue the execution reports made by
+    // `acknowledge` to the trader, then publish the new quote and report the trades to the owners
+    // of the resting orders it hit.
+    fn execute_order<F, A>(&self, symbol: &str, trader: &Trader, client_order_id: &str, side: Side, order: F, acknowledge: A) -> Result<(), String>
+    where
+        F: FnOnce(&mut OrderBook) -> Result<Report, String>,
+        A: FnOnce(&Report) -> Vec<OrderMessage>
+    {
+        let (report, updates) = {
+            let mut listings = self.listings.lock().unwrap();
+            let listing = listings.get_mut(symbol).ok_or(format!("{} instrument not found", symbol))?;
+            let report = order(&mut listing.book)?;
+            listing.trade(&report.fills);
+            // registered before the book is released so that no trade on the order is missed
+            let mut resting = self.resting.lock().unwrap();
+            let key = (symbol.to_string(), report.order);
+            if report.leaves > 0 {
+                resting.insert(key, RestingOrder { trader: trader.clone(), client_order_id: client_order_id.to_string(), side });
+            } else {
+                resting.remove(&key);
             }
+            drop(resting);
+            // acknowledged before the book is released so that no trade on the order reaches the trader first
+            for message in acknowledge(&report) {
+                trader.report(message);
+            }
+            listing.changes.extend(report.book.iter().copied());
+            (report, listing.order_updates(symbol))
+        };
+        for update in updates {
+            self.publish(symbol, update);
         }
-        Err(e) => {
-            eprintln!("ERROR JSON::{}", e);
+        self.report_fills(symbol, &report.fills);
+        Ok(())
+    }
+
+    // Send an execution report to the owner of each resting order in `fills`.
+    fn report_fills(&self, symbol: &str, fills: &[Fill]) {
+        let mut resting = self.resting.lock().unwrap();
+        for fill in fills.iter().filter(|f| f.maker_owner != MARKET_MAKER) {
+            let key = (symbol.to_string(), fill.maker);
+            if let Some(order) = resting.get(&key) {
+                order.trader.report(OrderMessage::ExecutionReport {
+                    client_order_id: order.client_order_id.to_string(),
+                    orig_client_order_id: None,
+                    order_id: fill.maker,
+                    symbol: symbol.to_string(),
+                    side: order.side,
+                    status: if fill.maker_leaves == 0 { Status::Filled } else { Status::PartiallyFilled },
+                    last_price: Some(fill.price),
+                    last_quantity: fill.quantity,
+                    filled: fill.maker_filled,
+                    leaves: fill.maker_leaves,
+                });
+            }
+            if fill.maker_leaves == 0 {
+                if let Some(order) = resting.remove(&key) {
+                    order.trader.orders.lock().unwrap().remove(&order.client_order_id);
+                }
+            }
+        }
+    }
+
+    // Cancel every resting order of `owner`, when its session ends.
+    fn cancel_all(&self, owner: Owner) {
+        let orders: Vec<(String, OrderId)> = self.resting.lock().unwrap().iter()
+            .filter(|(_, o)| o.trader.owner == owner)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for (symbol, id) in orders {
+            let updates = {
+                let mut listings = self.listings.lock().unwrap();
+                self.resting.lock().unwrap().remove(&(symbol.to_string(), id));
+                let Some(listing) = listings.get_mut(&symbol) else { continue };
+                let Ok(report) = listing.book.cancel(id) else { continue };
+                listing.changes.extend(report.book);
+                listing.order_updates(&symbol)
+            };
+            for update in updates {
+                self.publish(&symbol, update);
+            }
+        }
+    }
+
+    // Register a new client session, returning its id.
+    fn connect(&self, sender: SyncSender<Message>) -> usize {
+        let id = self.next_session.fetch_add(1, Ordering::SeqCst);
+        self.sessions.lock().unwrap().insert(id, Session { sender, symbols: HashSet::new() });
+        id
+    }
+
+    // Forget a session and all its subscriptions.
+    fn disconnect(&self, id: usize) {
+        self.sessions.lock().unwrap().remove(&id);
+    }
+
+    // True while a session is registered, a slow one is disconnected by `publish`.
+    fn is_connected(&self, id: usize) -> bool {
+        self.sessions.lock().unwrap().contains_key(&id)
+    }
+
+    // Answer a client request, returning the response to send back.
+    fn handle_request(&self, id: usize, request: Message) -> Option<Message> {
+        match request {
+            Message::Subscribe { symbol } => {
+                let (quote, depth) = match self.listings.lock().unwrap().get(&symbol) {
+                    Some(listing) => (listing.quote, listing.book.depth()),
+                    None => return Some(Message::Error { text: format!("{} instrument not found", symbol), symbol: Some(symbol) })
+                };
+                if let Some(session) = self.sessions.lock().unwrap().get_mut(&id) {
+                    session.symbols.insert(symbol.to_string());
+                }
+                self.subscribed.notify_all();
+                Some(Message::Snapshot { symbol, quote, depth })
+            }
+            Message::Unsubscribe { symbol } => {
+                if let Some(session) = self.sessions.lock().unwrap().get_mut(&id) {
+                    session.symbols.remove(&symbol);
+                }
+                None
+            }
+            other => Some(Message::Error { symbol: None, text: format!("unexpected request {:?}", other) })
+        }
+    }
+
+    // Send a message to every session subscribed to `symbol`, without waiting: a session whose
+    // queue is full does not keep up with the market and is disconnected. With backpressure the
+    // market waits for the sessions instead, out of the lock so that they can still make requests.
+    fn publish(&self, symbol: &str, message: Message) {
+        if self.backpressure {
+            let senders: Vec<(usize, SyncSender<Message>)> = self.sessions.lock().unwrap().iter()
+                .filter(|(_, s)| s.symbols.contains(symbol))
+                .map(|(id, s)| (*id, s.sender.clone()))
+                .collect();
+            for (id, sender) in senders {
+                if sender.send(message.clone()).is_err() {
+                    self.disconnect(id);
+                }
+            }
+            return;
+        }
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut slow = Vec::new();
+        for (id, session) in sessions.iter().filter(|(_, s)| s.symbols.contains(symbol)) {
+            if let Err(TrySendError::Full(_)) = session.sender.try_send(message.clone()) {
+                slow.push(*id);
+            }
+        }
+        for id in slow {
+            eprintln!("ERROR::session {} too slow, disconnected", id);
+            sessions.remove(&id);
+        }
+    }
+
+    // Move the prices of the symbols along their price process and stream them to the subscribers.
+    // Each symbol updates as a Poisson process of `rate` updates per second over the market,
+    // the market idles while nobody is subscribed and resumes where it stopped.
+    // With sessions, the market maker and the takers act in continuous trading only: the orders
+    // accumulate from the pre-open, and the auctions uncross at the price executing the most
+    // volume, which becomes the open or the close of the symbol. A closed book only cancels orders.
+    fn run(&self, clock: &Clock, rate: f64) {
+        let symbols: Vec<String> = self.listings.lock().unwrap().keys().cloned().collect();
+        if symbols.is_empty() || rate <= 0.0 {
+            return;
+        }
+        let interval = Exp::new(rate / symbols.len() as f64).unwrap();
+        let mut scheduler = Scheduler::new();
+        let mut previous = vec![Duration::ZERO; symbols.len()];
+        let mut begin = clock.now();
+        {
+            let mut listings = self.listings.lock().unwrap();
+            for (index, symbol) in symbols.iter().enumerate() {
+                let listing = listings.get_mut(symbol).unwrap();
+                listing.phase = self.phase(&listing.kind, Duration::ZERO);
+                listing.enter(listing.phase);
+                listing.changes.clear();
+                let next = Duration::from_secs_f64(interval.sample(&mut listing.rng));
+                match self.next_event(&listing.kind, Duration::ZERO, next) {
+                    Some(next) => scheduler.schedule(next, index),
+                    None => println!("{} closed", symbol)
+                }
+            }
+        }
+        while let Some((at, index)) = scheduler.next_event() {
+            clock.sleep_until(begin + at);
+            let symbol = &symbols[index];
+            let (updates, fills) = {
+                let mut listings = self.listings.lock().unwrap();
+                let listing = listings.get_mut(symbol).unwrap();
+                let phase = self.phase(&listing.kind, at);
+                let auction = Phase::auction_print(listing.phase, phase);
+                let entering = listing.phase != phase;
+                if entering {
+                    println!("{} enters {:?}", symbol, phase);
+                    listing.phase = phase;
+                }
+                let trading = phase == Phase::Continuous || auction.is_some();
+                let mut fills = Vec::new();
+                if trading {
+                    let dt = (at - previous[index]).as_secs_f64() / price_model::TRADING_DAY_SECS;
+                    let mid = listing.process.step(&mut listing.rng, dt);
+                    if let Some(auction) = auction {
+                        fills = listing.uncross(auction, phase, mid);
+                    }
+                    if phase == Phase::Continuous {
+                        fills.extend(listing.requote(mid));
+                    }
+                    if auction.is_none() {
+                        fills.extend(listing.take());
+                    }
+                } else if entering {
+                    listing.enter(phase);
+                }
+                // the price does not move while nothing trades
+                previous[index] = at;
+                let mut updates = Vec::new();
+                if trading || !listing.changes.is_empty() {
+                    updates.push(listing.update(symbol));
+                }
+                if entering {
+                    updates.push(listing.indicative(symbol));
+                }
+                let next = at + Duration::from_secs_f64(interval.sample(&mut listing.rng));
+                match self.next_event(&listing.kind, at, next) {
+                    Some(next) => scheduler.schedule(next, index),
+                    None => println!("{} closed", symbol)
+                }
+                (updates, fills)
+            };
+            for update in updates {
+                self.publish(symbol, update);
+            }
+            self.report_fills(symbol, &fills);
+            let idle = clock.now();
+            self.wait_for_subscribers();
+            begin += clock.now() - idle;
+        }
+    }
+
+    // Block while no session is subscribed to anything.
+    fn wait_for_subscribers(&self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        while !sessions.values().any(|s| !s.symbols.is_empty()) {
+            sessions = self.subscribed.wait(sessions).unwrap();
         }
     }
-    println!("<<<<<<<<<out load");
 }
 
-pub fn start_exchange() {
+// Run the exchange listing the symbols of `dictionary`, following the trading `sessions` when given.
+pub fn start_exchange(clock: Arc<Clock>, rate: f64, seed: u64, dictionary: Dictionary, sessions: Option<Arc<Sessions>>) -> Result<(), String> {
 
-    thread::spawn(|| {
-        load("./data.json");
+    for (k, s) in &dictionary.symbols {
+        println!("{} -> {:#?}", k, s);
+    }
+    // a virtual clock runs as fast as the clients read, the others do not wait for them
+    let market = Arc::new(Market::new(dictionary, seed, sessions, clock.mode() == Mode::Virtual));
+    let ticker = Arc::clone(&market);
+    let ticker_clock = Arc::clone(&clock);
+    thread::spawn(move || {
+        ticker.wait_for_subscribers();
+        ticker.run(&ticker_clock, rate);
     });
 
     println!("start listenibg");
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
+    let listener = TcpListener::bind("127.0.0.1:7878").map_err(|e| format!("exchange bind Error::{}", e))?;
     for stream in listener.incoming() //.take(2)
     {
-        let stream = stream.unwrap();
-        pool.execute(|| {
-            handle_connection(stream);
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Exchange socket error {}", e);
+                continue;
+            }
+        };
+        let clock = Arc::clone(&clock);
+        let market = Arc::clone(&market);
+        // each session has its own thread, it lasts as long as the client stays connected
+        thread::spawn(move || {
+            handle_connection(stream, &market, &clock);
         });
     }
     println!("Shutting down.");
+    Ok(())
 }
-fn handle_connection(mut stream: TcpStream) {
-    let mut buffer = [0; 1024];
+
+// Serve a connection, its first message tells a FIX session or an order entry
+// session (a logon) from a market data client.
+fn handle_connection(stream: TcpStream, market: &Arc<Market>, clock: &Clock) {
     println!("handle connection");
-    stream.read(&mut buffer).unwrap();
-    let mut sb = std::str::from_utf8(&buffer);
-
-    match sb {
-        Ok(s) => {
-            for line in 0..3 {
-                let contents = format!("line ::{}<p>", line);
-                let response = format!(
-                    "{}->{}",
-                    s,
-                    contents
-                );
-                stream.write_all(response.as_bytes()).unwrap();
-                println!("response::{}", response);
-                stream.flush().unwrap();
-                thread::sleep(Duration::from_secs(1));
-            }
-            println!("!!!!!!Exchange socket shutdown do");
-            stream.shutdown(std::net::Shutdown::Both);
-            println!("!!!!!!Exchange socket shutdown done");
-        }
-        _ => { return; }
+    let mut reader = match stream.try_clone() {
+        Ok(reader) => BufReader::new(reader),
+        Err(e) => {
+            eprintln!("Exchange socket error {}", e);
+            return;
+        }
+    };
+    match reader.fill_buf() {
+        Ok(first) if fix::is_fix(first) => return fix::handle_session(reader, stream, market),
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Exchange socket error {}", e);
+            return;
+        }
+    }
+    match protocol::read::<_, serde_json::Value>(&mut reader) {
+        Ok(Some(first)) if order_entry::is_logon(&first) => order_entry::handle_session(first, reader, stream, market),
+        Ok(Some(first)) => handle_market_data(first, reader, stream, market, clock),
+        Ok(None) => {}
+        Err(e) => eprintln!("Exchange socket error {}", e)
+    }
+}
+
+// Serve a market data client opened by `first`: requests are read on their own thread while
+// this one writes the responses, the updates of the subscribed symbols and the heartbeats,
+// until the session is disconnected for being slow.
+fn handle_market_data(first: serde_json::Value, mut reader: BufReader<TcpStream>, mut stream: TcpStream, market: &Arc<Market>, clock: &Clock) {
+    let (sender, receiver) = mpsc::sync_channel(SESSION_QUEUE);
+    let id = market.connect(sender.clone());
+
+    let requests = Arc::clone(market);
+    thread::spawn(move || {
+        let mut request = serde_json::from_value::<Message>(first)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+            .map(Some);
+        loop {
+            match request {
+                Ok(Some(request)) => {
+                    if let Some(response) = requests.handle_request(id, request) {
+                        if sender.send(response).is_err() {
+                            break;
+                        }
+                    }
+                }
+                Ok(None) => break,
+                Err(e) if e.kind() == ErrorKind::InvalidData => {
+                    if sender.send(Message::Error { symbol: None, text: e.to_string() }).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break
+            }
+            request = protocol::read(&mut reader);
+        }
+        requests.disconnect(id);
+    });
+
+    loop {
+        let message = match receiver.recv_timeout(HEARTBEAT) {
+            Ok(message) => message,
+            Err(RecvTimeoutError::Timeout) if !market.is_connected(id) => {
+                let _ = protocol::write(&mut stream, &Message::Error { symbol: None, text: "updates not read in time, disconnected".to_string() });
+                break;
+            }
+            Err(RecvTimeoutError::Timeout) => Message::Heartbeat { time: clock.now().as_secs_f64() },
+            Err(RecvTimeoutError::Disconnected) => break
+        };
+        if let Err(e) = protocol::write(&mut stream, &message) {
+            eprintln!("Exchange socket error {}", e);
+            break;
+        }
     }
+    market.disconnect(id);
+    println!("!!!!!!Exchange socket shutdown do");
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+    println!("!!!!!!Exchange socket shutdown done");
 }
 /*
-   fn handle_connection(mut stream: TcpStream) {
+   fn handle_connection(mut stream: TcpStream, clock: &Clock) {
    let mut buffer = [0; 1024];
    println!("handle connection");
    stream.read(&mut buffer).unwrap();
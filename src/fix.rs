@@ -0,0 +1,631 @@
+// FIX 4.4 acceptor of the exchange simulator.
+// A connection starting with "8=FIX" is a FIX session: after the logon it can enter orders
+// (NewOrderSingle, OrderCancelRequest, OrderCancelReplaceRequest), receive their execution
+// reports and request market data on the symbols of the dictionary.
+// The simulator does not keep the messages it sent, a resend request is answered by a gap fill.
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::order_book::{Side, Status};
+use super::order_entry::{NewOrder, OrderMessage, OrderType, Session, Trader};
+use super::{Market, SESSION_QUEUE};
+use crate::protocol::{Message, Quote};
+
+const SOH: u8 = 0x01;
+const BEGIN_STRING: &str = "FIX.4.4";
+// Heartbeat interval used when the logon does not set one, in seconds.
+const DEFAULT_HEARTBEAT: u64 = 30;
+// Longest body accepted from a peer, and longest field of the header and trailer, in bytes.
+const MAX_BODY_LENGTH: usize = 64 * 1024;
+const MAX_FIELD_LENGTH: u64 = 64;
+
+// A FIX message: its type (tag 35) and its body fields in order,
+// the standard header and trailer are added when it is encoded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FixMessage {
+    msg_type: String,
+    fields: Vec<(u32, String)>,
+}
+
+impl FixMessage {
+    pub fn new(msg_type: &str) -> FixMessage {
+        FixMessage { msg_type: msg_type.to_string(), fields: Vec::new() }
+    }
+
+    // Append a field.
+    pub fn with<T: ToString>(mut self, tag: u32, value: T) -> FixMessage {
+        self.fields.push((tag, value.to_string()));
+        self
+    }
+
+    // First value of `tag`.
+    pub fn get(&self, tag: u32) -> Option<&str> {
+        self.fields.iter().find(|(t, _)| *t == tag).map(|(_, v)| v.as_str())
+    }
+
+    // Every value of a repeated `tag`, in order.
+    pub fn get_all(&self, tag: u32) -> Vec<&str> {
+        self.fields.iter().filter(|(t, _)| *t == tag).map(|(_, v)| v.as_str()).collect()
+    }
+
+    // Value of a required `tag`, the error is the missing tag.
+    fn required(&self, tag: u32) -> Result<&str, u32> {
+        self.get(tag).ok_or(tag)
+    }
+
+    // Encode with the standard header and trailer, `poss_dup` for a message sent again.
+    fn encode(&self, sender: &str, target: &str, seq: u64, poss_dup: bool) -> Vec<u8> {
+        let now = sending_time();
+        let mut body = format!("35={}\x0149={}\x0156={}\x0134={}\x0152={}\x01", self.msg_type, sender, target, seq, now);
+        if poss_dup {
+            body.push_str(&format!("43=Y\x01122={}\x01", now));
+        }
+        for (tag, value) in &self.fields {
+            body.push_str(&format!("{}={}\x01", tag, value));
+        }
+        let mut message = format!("8={}\x019={}\x01{}", BEGIN_STRING, body.len(), body).into_bytes();
+        let checksum = checksum(&message);
+        message.extend(format!("10={:03}\x01", checksum).into_bytes());
+        message
+    }
+}
+
+fn sending_time() -> String {
+    chrono::Utc::now().format("%Y%m%d-%H:%M:%S%.3f").to_string()
+}
+
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().map(|&b| b as u32).sum::<u32>() % 256
+}
+
+// Read one "tag=value" field of at most `limit` bytes, appending its bytes to `raw`.
+fn read_field<R: BufRead>(reader: &mut R, raw: &mut Vec<u8>, limit: u64) -> io::Result<Option<(u32, String)>> {
+    let start = raw.len();
+    if reader.by_ref().take(limit).read_until(SOH, raw)? == 0 {
+        return Ok(None);
+    }
+    let field = String::from_utf8_lossy(&raw[start..]).trim_end_matches('\x01').to_string();
+    match field.split_once('=').map(|(tag, value)| (tag.parse::<u32>(), value)) {
+        Some((Ok(tag), value)) => Ok(Some((tag, value.to_string()))),
+        _ => Err(io::Error::new(ErrorKind::InvalidData, format!("malformed field '{}'", field)))
+    }
+}
+
+// Read the next message, `None` when the peer closed the connection.
+// A garbled message (begin string, body length or checksum) is reported as an `InvalidData` error,
+// and so is a body length above `MAX_BODY_LENGTH`, before anything is allocated for it.
+pub fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<FixMessage>> {
+    let garbled = |text: String| io::Error::new(ErrorKind::InvalidData, text);
+    let mut raw = Vec::new();
+    match read_field(reader, &mut raw, MAX_FIELD_LENGTH)? {
+        None => return Ok(None),
+        Some((8, begin)) if begin == BEGIN_STRING => {}
+        Some((tag, value)) => return Err(garbled(format!("begin string expected, got {}={}", tag, value)))
+    }
+    let length = match read_field(reader, &mut raw, MAX_FIELD_LENGTH)? {
+        Some((9, length)) => length.parse::<usize>().map_err(|e| garbled(e.to_string()))?,
+        _ => return Err(garbled("body length expected".to_string()))
+    };
+    if length > MAX_BODY_LENGTH {
+        return Err(garbled(format!("body length {} above {}", length, MAX_BODY_LENGTH)));
+    }
+    let header = raw.len();
+    raw.resize(header + length, 0);
+    reader.read_exact(&mut raw[header..])?;
+    let expected = checksum(&raw);
+    match read_field(reader, &mut Vec::new(), MAX_FIELD_LENGTH)? {
+        Some((10, value)) if value.parse::<u32>() == Ok(expected) => {}
+        _ => return Err(garbled(format!("checksum {:03} expected", expected)))
+    }
+
+    let mut body = &raw[header..];
+    let mut fields = Vec::new();
+    while let Some(field) = read_field(&mut body, &mut Vec::new(), MAX_BODY_LENGTH as u64)? {
+        fields.push(field);
+    }
+    match fields.first() {
+        Some((35, msg_type)) => Ok(Some(FixMessage { msg_type: msg_type.to_string(), fields: fields.split_off(1) })),
+        _ => Err(garbled("message type expected".to_string()))
+    }
+}
+
+// True when the first bytes of a connection open a FIX session.
+pub fn is_fix(first: &[u8]) -> bool {
+    first.starts_with(b"8=FIX")
+}
+
+// What the writer of a session is asked to send.
+enum Outgoing {
+    Fix(FixMessage),
+    GapFill(u64),           // answer a resend request from this sequence number
+    Logout(Option<String>), // send a logout then close the session
+}
+
+// Identity and shared state of a logged on session.
+struct SessionState {
+    sender_comp_id: String,
+    target_comp_id: String,
+    heartbeat: Duration,
+    last_received: Mutex<Instant>,
+    next_exec_id: AtomicU64,
+    executed: Mutex<HashMap<String, (f64, u64)>>,   // order id -> traded value and quantity
+    subscriptions: Mutex<HashMap<String, String>>,  // symbol -> MDReqID
+}
+
+impl SessionState {
+    // Translate an execution report of the order entry sessions.
+    fn execution_report(&self, report: OrderMessage) -> Option<FixMessage> {
+        let OrderMessage::ExecutionReport { client_order_id, orig_client_order_id, order_id, symbol, side, status, last_price, last_quantity, filled, leaves } = report else {
+            return None;
+        };
+        let order_id = format!("{}:{}", symbol, order_id);
+        let (exec_type, ord_status) = match status {
+            Status::New => ("0", "0"),
+            Status::PartiallyFilled => ("F", "1"),
+            Status::Filled => ("F", "2"),
+            Status::Cancelled => ("4", "4"),
+            Status::Replaced => ("5", if filled > 0 { "1" } else { "0" }),
+        };
+        let average = {
+            let mut executed = self.executed.lock().unwrap();
+            let (value, quantity) = executed.entry(order_id.to_string()).or_insert((0.0, 0));
+            if let Some(price) = last_price {
+                *value += price * last_quantity as f64;
+                *quantity += last_quantity;
+            }
+            if *quantity > 0 { *value / *quantity as f64 } else { 0.0 }
+        };
+        let mut message = FixMessage::new("8")
+            .with(37, &order_id)
+            .with(11, client_order_id);
+        if let Some(orig) = orig_client_order_id {
+            message = message.with(41, orig);
+        }
+        message = message
+            .with(17, self.next_exec_id.fetch_add(1, Ordering::SeqCst))
+            .with(150, exec_type)
+            .with(39, ord_status)
+            .with(55, symbol)
+            .with(54, side_code(side))
+            .with(38, filled + leaves);
+        if let Some(price) = last_price {
+            message = message.with(32, last_quantity).with(31, price);
+        }
+        Some(message.with(151, leaves).with(14, filled).with(6, average))
+    }
+
+    // Execution report of an order refused by the exchange.
+    fn order_reject(&self, client_order_id: &str, symbol: &str, side: &str, text: &str) -> FixMessage {
+        FixMessage::new("8")
+            .with(37, "NONE")
+            .with(11, client_order_id)
+            .with(17, self.next_exec_id.fetch_add(1, Ordering::SeqCst))
+            .with(150, 8)
+            .with(39, 8)
+            .with(55, symbol)
+            .with(54, side)
+            .with(151, 0)
+            .with(14, 0)
+            .with(6, 0)
+            .with(58, text)
+    }
+
+    // Translate a market data update of a subscribed symbol.
+    fn incremental_refresh(&self, update: Message) -> Option<FixMessage> {
+        let Message::Update { symbol, quote, .. } = update else {
+            return None;
+        };
+        let request = self.subscriptions.lock().unwrap().get(&symbol).cloned()?;
+        let entries = md_entries(&quote);
+        let mut message = FixMessage::new("X").with(262, request).with(268, entries.len());
+        for (entry_type, price) in entries {
+            message = message.with(279, 1).with(269, entry_type).with(55, &symbol).with(270, price);
+        }
+        Some(message)
+    }
+}
+
+fn side_code(side: Side) -> &'static str {
+    match side {
+        Side::Buy => "1",
+        Side::Sell => "2",
+    }
+}
+
+// Bid, offer and trade entries of a quote, a side without price is left out.
+fn md_entries(quote: &Quote) -> Vec<(u32, f64)> {
+    [(0, quote.bid), (1, quote.ask), (2, quote.last)].into_iter()
+        .filter(|(_, price)| *price > 0.0)
+        .collect()
+}
+
+// Serve a FIX session, its logon is the first message read from `reader`: messages are read
+// on their own thread while this one numbers and writes the responses, the execution
+// reports, the market data and the heartbeats.
+pub fn handle_session(mut reader: BufReader<TcpStream>, mut stream: TcpStream, market: &Arc<Market>) {
+    let logon = match read_message(&mut reader) {
+        Ok(Some(logon)) if logon.msg_type == "A" => logon,
+        Ok(Some(other)) => {
+            eprintln!("FIX logon expected, got {}", other.msg_type);
+            return;
+        }
+        Ok(None) => return,
+        Err(e) => {
+            eprintln!("FIX error {}", e);
+            return;
+        }
+    };
+    let (Some(sender), Some(target), Some(Ok(seq))) = (logon.get(49), logon.get(56), logon.get(34).map(|s| s.parse::<u64>())) else {
+        eprintln!("FIX logon without comp ids or sequence number");
+        return;
+    };
+    let heartbeat = logon.get(108).and_then(|h| h.parse::<u64>().ok()).unwrap_or(DEFAULT_HEARTBEAT).max(1);
+    let state = Arc::new(SessionState {
+        sender_comp_id: target.to_string(),
+        target_comp_id: sender.to_string(),
+        heartbeat: Duration::from_secs(heartbeat),
+        last_received: Mutex::new(Instant::now()),
+        next_exec_id: AtomicU64::new(1),
+        executed: Mutex::new(HashMap::new()),
+        subscriptions: Mutex::new(HashMap::new()),
+    });
+    println!("FIX logon {}", state.target_comp_id);
+
+    let (outgoing, receiver) = mpsc::sync_channel(SESSION_QUEUE);
+    let mut reply = FixMessage::new("A").with(98, 0).with(108, heartbeat);
+    if logon.get(141) == Some("Y") {
+        reply = reply.with(141, "Y");
+    }
+    let _ = outgoing.send(Outgoing::Fix(reply));
+
+    // the execution reports and the updates of subscribed symbols are translated on their way
+    let (order_sender, order_receiver) = mpsc::sync_channel(SESSION_QUEUE);
+    let trader = Trader::new(market.new_owner(), order_sender);
+    let reports = Arc::clone(&state);
+    forward(order_receiver, outgoing.clone(), move |report| reports.execution_report(report));
+    let (md_sender, md_receiver) = mpsc::sync_channel(SESSION_QUEUE);
+    let md_session = market.connect(md_sender);
+    let updates = Arc::clone(&state);
+    forward(md_receiver, outgoing.clone(), move |update| updates.incremental_refresh(update));
+
+    let connected = Arc::clone(&trader.connected);
+    let requests = Arc::clone(market);
+    let reader_state = Arc::clone(&state);
+    thread::spawn(move || {
+        let mut session = FixSession { market: &requests, orders: Session::new(&requests, &trader), md_session, state: &reader_state, outgoing };
+        session.read(reader, seq + 1);
+        requests.disconnect(md_session);
+        requests.cancel_all(trader.owner);
+    });
+
+    write_session(&mut stream, &state, receiver, &connected);
+    println!("FIX logout {}", state.target_comp_id);
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+}
+
+// Translate the messages of `receiver` to the writer of the session.
+fn forward<T, F>(receiver: Receiver<T>, outgoing: SyncSender<Outgoing>, translate: F)
+    where T: Send + 'static, F: Fn(T) -> Option<FixMessage> + Send + 'static {
+    thread::spawn(move || {
+        while let Ok(message) = receiver.recv() {
+            if let Some(message) = translate(message) {
+                if outgoing.send(Outgoing::Fix(message)).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+}
+
+// Number and write the outgoing messages. A heartbeat is sent when nothing was sent for an
+// interval, a test request when the peer was silent for longer and the session is closed
+// when the test request stays unanswered.
+fn write_session(stream: &mut TcpStream, state: &SessionState, receiver: Receiver<Outgoing>, connected: &AtomicBool) {
+    let mut seq = 0;
+    let mut last_sent = Instant::now();
+    let mut test_request = false;
+    let poll = state.heartbeat.min(Duration::from_secs(1));
+    loop {
+        let (message, poss_dup_seq, close) = match receiver.recv_timeout(poll) {
+            Ok(Outgoing::Fix(message)) => (message, None, false),
+            Ok(Outgoing::GapFill(begin)) => (FixMessage::new("4").with(123, "Y").with(36, seq + 1), Some(begin), false),
+            Ok(Outgoing::Logout(text)) => (text.into_iter().fold(FixMessage::new("5"), |m, text| m.with(58, text)), None, true),
+            Err(RecvTimeoutError::Timeout) if !connected.load(Ordering::SeqCst) => {
+                (FixMessage::new("5").with(58, "executions not read in time"), None, true)
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                let silence = state.last_received.lock().unwrap().elapsed();
+                if silence < state.heartbeat {
+                    test_request = false;
+                }
+                if test_request && silence > state.heartbeat * 5 / 2 {
+                    (FixMessage::new("5").with(58, "test request not answered"), None, true)
+                } else if !test_request && silence > state.heartbeat * 3 / 2 {
+                    test_request = true;
+                    (FixMessage::new("1").with(112, format!("TEST{}", seq + 1)), None, false)
+                } else if last_sent.elapsed() >= state.heartbeat {
+                    (FixMessage::new("0"), None, false)
+                } else {
+                    continue;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break
+        };
+        let raw = match poss_dup_seq {
+            Some(begin) => message.encode(&state.sender_comp_id, &state.target_comp_id, begin, true),
+            None => {
+                seq += 1;
+                message.encode(&state.sender_comp_id, &state.target_comp_id, seq, false)
+            }
+        };
+        if let Err(e) = stream.write_all(&raw).and_then(|_| stream.flush()) {
+            eprintln!("FIX socket error {}", e);
+            break;
+        }
+        last_sent = Instant::now();
+        if close {
+            break;
+        }
+    }
+}
+
+// Requests side of a logged on session.
+struct FixSession<'a> {
+    market: &'a Market,
+    orders: Session<'a>,
+    md_session: usize,
+    state: &'a SessionState,
+    outgoing: SyncSender<Outgoing>,
+}
+
+impl FixSession<'_> {
+    // Read and serve the messages of the peer until it logs out, disconnects or
+    // sends a sequence number lower than expected. A message after a gap is dropped and the
+    // gap requested once, the peer sending the message again with the ones it missed.
+    fn read(&mut self, mut reader: BufReader<TcpStream>, mut expected: u64) {
+        let mut requested = None;
+        loop {
+            let message = match read_message(&mut reader) {
+                Ok(Some(message)) => message,
+                Ok(None) => return,
+                Err(e) if e.kind() == ErrorKind::InvalidData => {
+                    // a garbled message is ignored, the gap it leaves is detected on the next one
+                    eprintln!("FIX garbled message {}", e);
+                    continue;
+                }
+                Err(_) => return
+            };
+            *self.state.last_received.lock().unwrap() = Instant::now();
+            let Some(Ok(seq)) = message.get(34).map(|s| s.parse::<u64>()) else {
+                let _ = self.outgoing.send(Outgoing::Logout(Some("MsgSeqNum missing".to_string())));
+                return;
+            };
+            if message.msg_type == "4" && message.get(123) != Some("Y") {
+                // sequence reset in reset mode, the sequence number of the message is ignored
+                if let Some(Ok(new_seq)) = message.get(36).map(|s| s.parse::<u64>()) {
+                    expected = new_seq;
+                }
+                continue;
+            }
+            if seq < expected {
+                if message.get(43) == Some("Y") {
+                    continue;
+                }
+                let text = format!("MsgSeqNum too low, expecting {} but received {}", expected, seq);
+                let _ = self.outgoing.send(Outgoing::Logout(Some(text)));
+                return;
+            }
+            if seq > expected {
+                if requested != Some(expected) {
+                    requested = Some(expected);
+                    let _ = self.outgoing.send(Outgoing::Fix(FixMessage::new("2").with(7, expected).with(16, 0)));
+                }
+                continue;
+            }
+            expected = seq + 1;
+
+            let responses = match message.msg_type.as_str() {
+                "0" | "3" => Vec::new(),
+                "1" => vec![FixMessage::new("0").with(112, message.get(112).unwrap_or_default())],
+                "2" => {
+                    let begin = message.get(7).and_then(|b| b.parse::<u64>().ok()).unwrap_or(1);
+                    let _ = self.outgoing.send(Outgoing::GapFill(begin));
+                    Vec::new()
+                }
+                "4" => {
+                    // gap fill, it only moves the expected sequence number forward
+                    if let Some(Ok(new_seq)) = message.get(36).map(|s| s.parse::<u64>()) {
+                        expected = expected.max(new_seq);
+                    }
+                    Vec::new()
+                }
+                "5" => {
+                    let _ = self.outgoing.send(Outgoing::Logout(None));
+                    return;
+                }
+                "D" => self.new_order_single(seq, &message),
+                "F" | "G" => self.cancel_request(seq, &message),
+                "V" => self.market_data_request(seq, &message),
+                other => vec![FixMessage::new("j").with(45, seq).with(372, other).with(380, 3).with(58, "unsupported message type")]
+            };
+            for response in responses {
+                if self.outgoing.send(Outgoing::Fix(response)).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    // Session level reject of a message missing a required tag.
+    fn missing_tag(seq: u64, msg_type: &str, tag: u32) -> Vec<FixMessage> {
+        vec![FixMessage::new("3").with(45, seq).with(371, tag).with(372, msg_type).with(373, 1).with(58, "required tag missing")]
+    }
+
+    fn new_order_single(&mut self, seq: u64, message: &FixMessage) -> Vec<FixMessage> {
+        let (client_order_id, symbol, side, quantity, order_type) =
+            match (message.required(11), message.required(55), message.required(54), message.required(38), message.required(40)) {
+                (Ok(id), Ok(symbol), Ok(side), Ok(quantity), Ok(order_type)) => (id, symbol, side, quantity, order_type),
+                (Err(tag), ..) | (_, Err(tag), ..) | (_, _, Err(tag), ..) | (.., Err(tag), _) | (.., Err(tag)) => return FixSession::missing_tag(seq, "D", tag)
+            };
+        let state = self.state;
+        let reject = |text: &str| vec![state.order_reject(client_order_id, symbol, side, text)];
+        let side = match side {
+            "1" => Side::Buy,
+            "2" => Side::Sell,
+            other => return reject(&format!("unsupported side {}", other))
+        };
+        let order_type = match order_type {
+            "1" => OrderType::Market,
+            "2" => OrderType::Limit,
+            other => return reject(&format!("unsupported order type {}", other))
+        };
+        let Ok(quantity) = quantity.parse::<u64>() else {
+            return reject(&format!("quantity should be a whole number, but is {}", quantity));
+        };
+        let price = message.get(44).and_then(|p| p.parse::<f64>().ok());
+        let order = NewOrder { client_order_id: client_order_id.to_string(), symbol: symbol.to_string(), side, order_type, price, quantity };
+        self.orders.new_order(seq, order).into_iter()
+            .filter_map(|response| match response {
+                OrderMessage::Reject { text, .. } => Some(state.order_reject(client_order_id, symbol, side_code(side), &text)),
+                report => state.execution_report(report)
+            })
+            .collect()
+    }
+
+    // OrderCancelRequest (F) or OrderCancelReplaceRequest (G).
+    fn cancel_request(&mut self, seq: u64, message: &FixMessage) -> Vec<FixMessage> {
+        let (client_order_id, orig_client_order_id) = match (message.required(11), message.required(41)) {
+            (Ok(id), Ok(orig)) => (id, orig),
+            (Err(tag), _) | (_, Err(tag)) => return FixSession::missing_tag(seq, &message.msg_type, tag)
+        };
+        let replace = message.msg_type == "G";
+        let responses = if replace {
+            let price = message.get(44).and_then(|p| p.parse::<f64>().ok());
+            let quantity = message.get(38).and_then(|q| q.parse::<u64>().ok());
+            match (price, quantity) {
+                (Some(price), Some(quantity)) => self.orders.replace(seq, client_order_id.to_string(), orig_client_order_id.to_string(), price, quantity),
+                _ => vec![OrderMessage::Reject { ref_seq: Some(seq), client_order_id: None, text: "replace needs a price and a quantity".to_string() }]
+            }
+        } else {
+            self.orders.cancel(seq, client_order_id.to_string(), orig_client_order_id.to_string())
+        };
+        responses.into_iter()
+            .filter_map(|response| match response {
+                OrderMessage::Reject { text, .. } => Some(FixMessage::new("9")
+                    .with(37, "NONE")
+                    .with(11, client_order_id)
+                    .with(41, orig_client_order_id)
+                    .with(39, 8)
+                    .with(434, if replace { 2 } else { 1 })
+                    .with(102, 1)
+                    .with(58, text)),
+                report => self.state.execution_report(report)
+            })
+            .collect()
+    }
+
+    // MarketDataRequest (V): a snapshot, a subscription answered by a snapshot then
+    // incremental refreshes, or the end of a subscription.
+    fn market_data_request(&mut self, seq: u64, message: &FixMessage) -> Vec<FixMessage> {
+        let (request, request_type) = match (message.required(262), message.required(263)) {
+            (Ok(request), Ok(request_type)) => (request, request_type),
+            (Err(tag), _) | (_, Err(tag)) => return FixSession::missing_tag(seq, "V", tag)
+        };
+        let md_reject = |reason: u32, text: String| FixMessage::new("Y").with(262, request).with(281, reason).with(58, text);
+        let symbols = message.get_all(55);
+        if symbols.is_empty() {
+            return FixSession::missing_tag(seq, "V", 55);
+        }
+        let mut responses = Vec::new();
+        for symbol in symbols {
+            let quote = match request_type {
+                "0" => self.market.listings.lock().unwrap().get(symbol).map(|listing| listing.quote),
+                "1" => {
+                    // registered first so that no update is lost between the snapshot and the refreshes
+                    self.state.subscriptions.lock().unwrap().insert(symbol.to_string(), request.to_string());
+                    match self.market.handle_request(self.md_session, Message::Subscribe { symbol: symbol.to_string() }) {
+                        Some(Message::Snapshot { quote, .. }) => Some(quote),
+                        _ => {
+                            self.state.subscriptions.lock().unwrap().remove(symbol);
+                            None
+                        }
+                    }
+                }
+                "2" => {
+                    self.state.subscriptions.lock().unwrap().remove(symbol);
+                    self.market.handle_request(self.md_session, Message::Unsubscribe { symbol: symbol.to_string() });
+                    continue;
+                }
+                other => return vec![md_reject(4, format!("unsupported subscription request type {}", other))]
+            };
+            let Some(quote) = quote else {
+                responses.push(md_reject(0, format!("{} instrument not found", symbol)));
+                continue;
+            };
+            let entries = md_entries(&quote);
+            let mut snapshot = FixMessage::new("W").with(262, request).with(55, symbol).with(268, entries.len());
+            for (entry_type, price) in entries {
+                snapshot = snapshot.with(269, entry_type).with(270, price);
+            }
+            responses.push(snapshot);
+        }
+        responses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoded_message_reads_back() {
+        let order = FixMessage::new("D").with(11, "ord-1").with(55, "AAPL").with(54, 1).with(38, 100);
+        let bytes = order.encode("EXCHANGE", "CLIENT", 7, false);
+        let message = read_message(&mut &bytes[..]).unwrap().unwrap();
+        assert_eq!(message.msg_type, "D");
+        assert_eq!(message.get(34), Some("7"));
+        assert_eq!(message.get(55), Some("AAPL"));
+        assert_eq!(message.get(38), Some("100"));
+        assert!(read_message(&mut &bytes[bytes.len()..]).unwrap().is_none());
+    }
+
+    #[test]
+    fn body_length_covers_the_body() {
+        let bytes = FixMessage::new("0").encode("EXCHANGE", "CLIENT", 1, false);
+        let text = String::from_utf8(bytes).unwrap();
+        let (header, rest) = text.split_once("\x0135=").unwrap();
+        let length: usize = header.rsplit_once("9=").unwrap().1.parse().unwrap();
+        let body = &rest[..rest.find("10=").unwrap()];
+        assert_eq!(length, body.len() + 3);
+    }
+
+    #[test]
+    fn bad_checksum_is_garbled() {
+        let mut bytes = FixMessage::new("0").encode("EXCHANGE", "CLIENT", 1, false);
+        let at = bytes.len() - 2;
+        bytes[at] = if bytes[at] == b'9' { b'0' } else { bytes[at] + 1 };
+        let error = read_message(&mut &bytes[..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn body_length_above_the_maximum_is_garbled() {
+        let bytes = b"8=FIX.4.4\x019=99999999999999\x01";
+        let error = read_message(&mut &bytes[..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        let bytes = format!("8=FIX.4.4\x019={}\x01", MAX_BODY_LENGTH + 1).into_bytes();
+        assert_eq!(read_message(&mut &bytes[..]).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn begin_string_is_required() {
+        let error = read_message(&mut &b"8=FIX.4.2\x019=5\x0135=0\x0110=000\x01"[..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,190 @@
+// Historical bars: one OHLCV CSV file per symbol, named after it like AAPL.csv, played through a data feed
+// with ticks interpolated inside each bar. A file starts with a header naming its columns, in any case:
+//   time,open,high,low,close,volume
+//   2024-03-04 09:00:00,45.97,46.10,45.90,46.02,12000
+// the time may be a date alone for daily bars, and its column may be named date or timestamp.
+use std::path::Path;
+use std::time::Duration;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use serde::{Deserialize, Deserializer};
+
+use crate::session::Phase;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Bar {
+    #[serde(alias = "date", alias = "timestamp", deserialize_with = "deserialize_time")]
+    pub time: NaiveDateTime, // start of the bar
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    #[serde(default)]
+    pub volume: u64,
+}
+
+// An interpolated trade of a bar, with the auction it prints at the start or the end of a day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tick {
+    pub time: NaiveDateTime,
+    pub price: f64,
+    pub size: u64,
+    pub print: Option<Phase>,
+}
+
+// Fewest ticks of a bar, going through its open, low, high and close.
+const MIN_TICKS: usize = 4;
+
+// Parse a bar time like 2024-03-04 09:00:00 or 2024-03-04T09:00, or a date alone like 2024-03-04.
+pub fn parse_time(time: &str) -> Result<NaiveDateTime, String> {
+    ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"].iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(time, format).ok())
+        .or_else(|| NaiveDate::parse_from_str(time, "%Y-%m-%d").ok().map(|date| date.and_time(NaiveTime::MIN)))
+        .ok_or_else(|| format!("bar time should be like 2024-03-04 09:00:00 or 2024-03-04, but is '{}'", time))
+}
+
+fn deserialize_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveDateTime, D::Error> {
+    let time = String::deserialize(deserializer)?;
+    parse_time(&time).map_err(serde::de::Error::custom)
+}
+
+// Load the bars of a CSV file in time order, a bar with a price that is not a number, or whose low and high
+// do not hold its open and close, is an error giving its line.
+pub fn load(path: &Path) -> Result<Vec<Bar>, String> {
+    let error = |e: csv::Error| format!("reading {}::{}", path.display(), e);
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_path(path).map_err(error)?;
+    let headers: csv::StringRecord = reader.headers().map_err(error)?.iter().map(str::to_lowercase).collect();
+    let mut bars = Vec::new();
+    let mut record = csv::StringRecord::new();
+    while reader.read_record(&mut record).map_err(error)? {
+        let line = record.position().map_or(0, |position| position.line());
+        let bar: Bar = record.deserialize(Some(&headers)).map_err(|e| format!("{} line {}::{}", path.display(), line, e))?;
+        if ![bar.open, bar.high, bar.low, bar.close].iter().all(|price| price.is_finite()) {
+            return Err(format!("{} line {}::bar at {} should have finite prices, but has open {}, high {}, low {} and close {}",
+                path.display(), line, bar.time, bar.open, bar.high, bar.low, bar.close));
+        }
+        if bar.low <= 0.0 || bar.low > bar.open.min(bar.close) || bar.high < bar.open.max(bar.close) {
+            return Err(format!("{} line {}::bar at {} should hold its open {} and close {} between its low {} and high {}",
+                path.display(), line, bar.time, bar.open, bar.close, bar.low, bar.high));
+        }
+        bars.push(bar);
+    }
+    bars.sort_by_key(|bar| bar.time);
+    Ok(bars)
+}
+
+// Length of the bars of a history: the shortest time between two of them, a day for a single bar.
+pub fn interval(bars: &[Bar]) -> Duration {
+    bars.windows(2)
+        .filter_map(|pair| (pair[1].time - pair[0].time).to_std().ok())
+        .filter(|gap| !gap.is_zero())
+        .min()
+        .unwrap_or(Duration::from_secs(24 * 3600))
+}
+
+// The `count` ticks of a bar of length `interval`, evenly spread over it and interpolated through
+// open, low, high and close for a rising bar, or open, high, low and close for a falling one.
+pub fn interpolate(bar: &Bar, interval: Duration, count: usize) -> impl Iterator<Item = (NaiveDateTime, f64)> {
+    let count = count.max(MIN_TICKS);
+    let path = if bar.close >= bar.open {
+        [bar.open, bar.low, bar.high, bar.close]
+    } else {
+        [bar.open, bar.high, bar.low, bar.close]
+    };
+    let start = bar.time;
+    (0..count).map(move |k| {
+        let along = (k * (path.len() - 1)) as f64 / (count - 1) as f64;
+        let segment = (along as usize).min(path.len() - 2);
+        let price = path[segment] + (path[segment + 1] - path[segment]) * (along - segment as f64);
+        let offset = interval.mul_f64(k as f64 / count as f64);
+        (start + chrono::TimeDelta::from_std(offset).unwrap(), price)
+    })
+}
+
+// The ticks of a history, `count` per bar sharing its volume, the last one trading what remains.
+// The first tick of a day prints its opening auction and the last one its closing auction.
+pub fn ticks(bars: Vec<Bar>, count: usize) -> impl Iterator<Item = Tick> {
+    let interval = interval(&bars);
+    let days: Vec<NaiveDate> = bars.iter().map(|bar| bar.time.date()).collect();
+    bars.into_iter().enumerate().flat_map(move |(b, bar)| {
+        let opens = b == 0 || days[b - 1] != days[b];
+        let closes = b + 1 == days.len() || days[b + 1] != days[b];
+        let last = count.max(MIN_TICKS) - 1;
+        let share = bar.volume / (last + 1) as u64;
+        let remains = bar.volume - share * last as u64;
+        interpolate(&bar, interval, count).enumerate().map(move |(k, (time, price))| {
+            let print = match k {
+                0 if opens => Some(Phase::OpeningAuction),
+                k if k == last && closes => Some(Phase::ClosingAuction),
+                _ => None
+            };
+            let size = if k == last { remains } else { share };
+            Tick { time, price, size, print }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn bar(time: &str, open: f64, high: f64, low: f64, close: f64, volume: u64) -> Bar {
+        Bar { time: parse_time(time).unwrap(), open, high, low, close, volume }
+    }
+
+    // Load the bars of a CSV file written with `contents`.
+    fn load_csv(name: &str, contents: &str) -> Result<Vec<Bar>, String> {
+        let path = std::env::temp_dir().join(format!("{}-{}.csv", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        let bars = load(&path);
+        fs::remove_file(&path).unwrap();
+        bars
+    }
+
+    #[test]
+    fn bars_load_in_time_order() {
+        let bars = load_csv("bars", "Date, Open, High, Low, Close, Volume\n2024-03-05,46,47,45.5,46.5,900\n2024-03-04,45,46.2,44.8,46,1200\n").unwrap();
+        assert_eq!(bars, vec![bar("2024-03-04", 45.0, 46.2, 44.8, 46.0, 1200), bar("2024-03-05", 46.0, 47.0, 45.5, 46.5, 900)]);
+    }
+
+    #[test]
+    fn bars_with_bad_prices_are_refused_with_their_line() {
+        let header = "time,open,high,low,close\n2024-03-04,45,46,44,45.5\n";
+        let error = load_csv("nan", &format!("{}2024-03-05,NaN,46,44,45.5\n", header)).unwrap_err();
+        assert!(error.contains("line 3::") && error.contains("finite"), "{}", error);
+        let error = load_csv("inf", &format!("{}2024-03-05,45,inf,44,45.5\n", header)).unwrap_err();
+        assert!(error.contains("line 3::"), "{}", error);
+        let error = load_csv("low", &format!("{}2024-03-05,45,46,45.2,45.5\n", header)).unwrap_err();
+        assert!(error.contains("line 3::") && error.contains("between its low"), "{}", error);
+    }
+
+    #[test]
+    fn interval_is_the_shortest_gap() {
+        let bars = vec![bar("2024-03-04 09:00", 1.0, 1.0, 1.0, 1.0, 0), bar("2024-03-04 09:05", 1.0, 1.0, 1.0, 1.0, 0), bar("2024-03-04 09:06", 1.0, 1.0, 1.0, 1.0, 0)];
+        assert_eq!(interval(&bars), Duration::from_secs(60));
+        assert_eq!(interval(&bars[..1]), Duration::from_secs(24 * 3600));
+    }
+
+    #[test]
+    fn ticks_go_through_the_low_and_high_in_the_direction_of_the_bar() {
+        let minute = Duration::from_secs(60);
+        let rising: Vec<(NaiveDateTime, f64)> = interpolate(&bar("2024-03-04 09:00", 10.0, 12.0, 9.0, 11.0, 0), minute, 4).collect();
+        let times: Vec<NaiveDateTime> = ["09:00:00", "09:00:15", "09:00:30", "09:00:45"].iter().map(|t| parse_time(&format!("2024-03-04 {}", t)).unwrap()).collect();
+        assert_eq!(rising, times.into_iter().zip([10.0, 9.0, 12.0, 11.0]).collect::<Vec<_>>());
+        let falling: Vec<f64> = interpolate(&bar("2024-03-04 09:00", 11.0, 12.0, 9.0, 10.0, 0), minute, 7).map(|(_, price)| price).collect();
+        assert_eq!(falling, vec![11.0, 11.5, 12.0, 10.5, 9.0, 9.5, 10.0]);
+    }
+
+    #[test]
+    fn ticks_share_the_volume_and_print_the_auctions_of_each_day() {
+        let bars = vec![
+            bar("2024-03-04 09:00", 10.0, 10.0, 10.0, 10.0, 10),
+            bar("2024-03-04 09:01", 10.0, 10.0, 10.0, 10.0, 0),
+            bar("2024-03-05 09:00", 10.0, 10.0, 10.0, 10.0, 4),
+        ];
+        let ticks: Vec<Tick> = ticks(bars, 4).collect();
+        assert_eq!(ticks.iter().map(|t| t.size).collect::<Vec<_>>(), vec![2, 2, 2, 4, 0, 0, 0, 0, 1, 1, 1, 1]);
+        let prints: Vec<(usize, Phase)> = ticks.iter().enumerate().filter_map(|(k, t)| t.print.map(|p| (k, p))).collect();
+        assert_eq!(prints, vec![(0, Phase::OpeningAuction), (7, Phase::ClosingAuction), (8, Phase::OpeningAuction), (11, Phase::ClosingAuction)]);
+    }
+}
@@ -0,0 +1,436 @@
+// Streaming technical indicators computed on the trades of an instrument, one price at a time.
+// An indicator is written like sma:20, ema:20, rsi:14, macd:12:26:9, bb:20:2 or atr:14:10, its parameters
+// defaulting to these when left out like in macd, and its values are published under that name
+// once it has seen enough prices. The periods are whole numbers up to 10000, the average true range
+// being computed over bars of a number of trades, 10 by default:
+//   "indicators": {"sma:20": 45.97, "macd:12:26:9": {"macd": 0.02, "signal": 0.01, "histogram": 0.01}}
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{self, Debug};
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+
+use crate::bars::{BarSpec, Builder};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Spec {
+    Sma(usize),                                          // simple moving average
+    Ema(usize),                                          // exponential moving average
+    Rsi(usize),                                          // relative strength index, Wilder smoothed
+    Macd { fast: usize, slow: usize, signal: usize },    // moving average convergence divergence
+    Bollinger { period: usize, width: f64 },             // bands `width` standard deviations around the average
+    Atr { period: usize, ticks: usize },                 // average true range of bars of `ticks` trades
+}
+
+// Longest period of an indicator.
+const MAX_PERIOD: usize = 10_000;
+
+impl FromStr for Spec {
+    type Err = String;
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let error = || format!("indicator should be like 'sma:20', 'ema:20', 'rsi:14', 'macd:12:26:9', 'bb:20:2' or 'atr:14:10', but is '{}'", spec);
+        let mut parts = spec.split(':');
+        let name = parts.next().unwrap_or_default();
+        let parameters: Vec<&str> = parts.collect();
+        let period = |at: usize, default: usize| match parameters.get(at) {
+            Some(p) => p.parse::<usize>().ok().filter(|p| (1..=MAX_PERIOD).contains(p)),
+            None => Some(default)
+        };
+        let width = match parameters.get(1) {
+            Some(w) => w.parse::<f64>().ok().filter(|w| w.is_finite() && *w > 0.0),
+            None => Some(2.0)
+        };
+        let spec = match (name, parameters.len()) {
+            ("sma", 0..=1) => period(0, 20).map(Spec::Sma),
+            ("ema", 0..=1) => period(0, 20).map(Spec::Ema),
+            ("rsi", 0..=1) => period(0, 14).map(Spec::Rsi),
+            ("atr", 0..=2) => period(0, 14).zip(period(1, 10)).map(|(period, ticks)| Spec::Atr { period, ticks }),
+            ("macd", 0 | 3) => match (period(0, 12), period(1, 26), period(2, 9)) {
+                (Some(fast), Some(slow), Some(signal)) if fast < slow => Some(Spec::Macd { fast, slow, signal }),
+                _ => None
+            },
+            ("bb" | "bollinger", 0..=2) => period(0, 20).zip(width).map(|(period, width)| Spec::Bollinger { period, width }),
+            _ => None
+        };
+        spec.ok_or_else(error)
+    }
+}
+
+impl fmt::Display for Spec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Spec::Sma(period) => write!(f, "sma:{}", period),
+            Spec::Ema(period) => write!(f, "ema:{}", period),
+            Spec::Rsi(period) => write!(f, "rsi:{}", period),
+            Spec::Macd { fast, slow, signal } => write!(f, "macd:{}:{}:{}", fast, slow, signal),
+            Spec::Bollinger { period, width } => write!(f, "bb:{}:{}", period, width),
+            Spec::Atr { period, ticks } => write!(f, "atr:{}:{}", period, ticks),
+        }
+    }
+}
+
+// Value of an indicator.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum Value {
+    Single(f64),
+    Macd { macd: f64, signal: f64, histogram: f64 },
+    Bands { lower: f64, middle: f64, upper: f64 },
+}
+
+// An indicator updated with each trade price.
+pub trait Indicator: Debug + Send {
+    // Add a price and return the new value, `None` while it has not seen enough prices.
+    fn update(&mut self, price: f64) -> Option<Value>;
+}
+
+// Build an indicator from its specification.
+pub fn create(spec: &Spec) -> Box<dyn Indicator> {
+    match *spec {
+        Spec::Sma(period) => Box::new(Sma::new(period)),
+        Spec::Ema(period) => Box::new(Ema::new(period)),
+        Spec::Rsi(period) => Box::new(Rsi::new(period)),
+        Spec::Macd { fast, slow, signal } => Box::new(Macd::new(fast, slow, signal)),
+        Spec::Bollinger { period, width } => Box::new(Bollinger::new(period, width)),
+        Spec::Atr { period, ticks } => Box::new(Atr::new(period, ticks)),
+    }
+}
+
+// The last `period` prices and their sum.
+#[derive(Debug)]
+struct Window {
+    period: usize,
+    prices: VecDeque<f64>,
+    sum: f64,
+}
+
+impl Window {
+    fn new(period: usize) -> Window {
+        Window { period, prices: VecDeque::new(), sum: 0.0 }
+    }
+
+    // Add a price, returning whether the window is full.
+    fn push(&mut self, price: f64) -> bool {
+        self.prices.push_back(price);
+        self.sum += price;
+        if self.prices.len() > self.period {
+            self.sum -= self.prices.pop_front().unwrap();
+        }
+        self.prices.len() == self.period
+    }
+
+    fn mean(&self) -> f64 {
+        self.sum / self.prices.len() as f64
+    }
+}
+
+#[derive(Debug)]
+struct Sma {
+    window: Window,
+}
+
+impl Sma {
+    fn new(period: usize) -> Sma {
+        Sma { window: Window::new(period) }
+    }
+}
+
+impl Indicator for Sma {
+    fn update(&mut self, price: f64) -> Option<Value> {
+        self.window.push(price).then(|| Value::Single(self.window.mean()))
+    }
+}
+
+// Exponential average seeded with the simple average of its first `period` values.
+#[derive(Debug)]
+struct Ema {
+    period: usize,
+    seen: usize,
+    value: f64,
+}
+
+impl Ema {
+    fn new(period: usize) -> Ema {
+        Ema { period, seen: 0, value: 0.0 }
+    }
+
+    fn average(&mut self, x: f64) -> Option<f64> {
+        self.seen += 1;
+        if self.seen <= self.period {
+            self.value += (x - self.value) / self.seen as f64;
+        } else {
+            self.value += (x - self.value) * 2.0 / (self.period + 1) as f64;
+        }
+        (self.seen >= self.period).then_some(self.value)
+    }
+}
+
+impl Indicator for Ema {
+    fn update(&mut self, price: f64) -> Option<Value> {
+        self.average(price).map(Value::Single)
+    }
+}
+
+// Wilder average, seeded with the simple average of its first `period` values.
+#[derive(Debug)]
+struct Wilder {
+    period: usize,
+    seen: usize,
+    value: f64,
+}
+
+impl Wilder {
+    fn new(period: usize) -> Wilder {
+        Wilder { period, seen: 0, value: 0.0 }
+    }
+
+    fn average(&mut self, x: f64) -> Option<f64> {
+        self.seen += 1;
+        let weight = self.seen.min(self.period) as f64;
+        self.value += (x - self.value) / weight;
+        (self.seen >= self.period).then_some(self.value)
+    }
+}
+
+#[derive(Debug)]
+struct Rsi {
+    previous: Option<f64>,
+    gains: Wilder,
+    losses: Wilder,
+}
+
+impl Rsi {
+    fn new(period: usize) -> Rsi {
+        Rsi { previous: None, gains: Wilder::new(period), losses: Wilder::new(period) }
+    }
+}
+
+impl Indicator for Rsi {
+    fn update(&mut self, price: f64) -> Option<Value> {
+        let change = price - self.previous.replace(price)?;
+        let gain = self.gains.average(change.max(0.0));
+        let loss = self.losses.average((-change).max(0.0));
+        let (gain, loss) = (gain?, loss?);
+        let rsi = if loss > 0.0 {
+            100.0 - 100.0 / (1.0 + gain / loss)
+        } else if gain > 0.0 {
+            100.0
+        } else {
+            50.0 // the price did not move
+        };
+        Some(Value::Single(rsi))
+    }
+}
+
+#[derive(Debug)]
+struct Macd {
+    fast: Ema,
+    slow: Ema,
+    signal: Ema,
+}
+
+impl Macd {
+    fn new(fast: usize, slow: usize, signal: usize) -> Macd {
+        Macd { fast: Ema::new(fast), slow: Ema::new(slow), signal: Ema::new(signal) }
+    }
+}
+
+impl Indicator for Macd {
+    fn update(&mut self, price: f64) -> Option<Value> {
+        let (fast, slow) = (self.fast.average(price), self.slow.average(price));
+        let macd = fast? - slow?;
+        let signal = self.signal.average(macd)?;
+        Some(Value::Macd { macd, signal, histogram: macd - signal })
+    }
+}
+
+#[derive(Debug)]
+struct Bollinger {
+    window: Window,
+    width: f64,
+}
+
+impl Bollinger {
+    fn new(period: usize, width: f64) -> Bollinger {
+        Bollinger { window: Window::new(period), width }
+    }
+}
+
+impl Indicator for Bollinger {
+    fn update(&mut self, price: f64) -> Option<Value> {
+        if !self.window.push(price) {
+            return None;
+        }
+        let middle = self.window.mean();
+        let variance = self.window.prices.iter().map(|p| (p - middle).powi(2)).sum::<f64>() / self.window.period as f64;
+        let band = self.width * variance.sqrt();
+        Some(Value::Bands { lower: middle - band, middle, upper: middle + band })
+    }
+}
+
+// The trades are grouped in bars of a number of trades, the true range of a bar spanning its high
+// and low and the close of the bar before it. The value changes as each bar completes.
+#[derive(Debug)]
+struct Atr {
+    bars: Builder,
+    close: Option<f64>,
+    range: Wilder,
+    value: Option<f64>,
+}
+
+impl Atr {
+    fn new(period: usize, ticks: usize) -> Atr {
+        Atr { bars: Builder::new(BarSpec::Ticks(ticks)), close: None, range: Wilder::new(period), value: None }
+    }
+}
+
+impl Indicator for Atr {
+    fn update(&mut self, price: f64) -> Option<Value> {
+        if let Some(bar) = self.bars.update(0.0, price, 0) {
+            let (high, low) = match self.close.replace(bar.close) {
+                Some(close) => (bar.high.max(close), bar.low.min(close)),
+                None => (bar.high, bar.low)
+            };
+            self.value = self.range.average(high - low);
+        }
+        self.value.map(Value::Single)
+    }
+}
+
+// The indicators attached to an instrument and their last values.
+#[derive(Debug, Default)]
+pub struct Indicators {
+    attached: Vec<(Spec, Box<dyn Indicator>, Option<Value>)>,
+}
+
+impl Indicators {
+    // Attach an indicator, starting from the next price.
+    pub fn attach(&mut self, spec: Spec) -> Result<(), String> {
+        if self.attached.iter().any(|(s, _, _)| *s == spec) {
+            return Err(format!("{} already attached", spec));
+        }
+        self.attached.push((spec, create(&spec), None));
+        Ok(())
+    }
+
+    pub fn detach(&mut self, spec: &Spec) -> Result<(), String> {
+        let position = self.attached.iter().position(|(s, _, _)| s == spec).ok_or(format!("{} not attached", spec))?;
+        self.attached.remove(position);
+        Ok(())
+    }
+
+    // Add a trade price to every indicator.
+    pub fn update(&mut self, price: f64) {
+        for (_, indicator, value) in self.attached.iter_mut() {
+            *value = indicator.update(price);
+        }
+    }
+
+    // The values of the indicators by name, leaving out those still warming up.
+    pub fn values(&self) -> BTreeMap<String, Value> {
+        self.attached.iter()
+            .filter_map(|(spec, _, value)| value.map(|value| (spec.to_string(), value)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(spec: &str, prices: &[f64]) -> Vec<Option<Value>> {
+        let mut indicator = create(&spec.parse().unwrap());
+        prices.iter().map(|price| indicator.update(*price)).collect()
+    }
+
+    fn single(value: Option<Value>) -> f64 {
+        match value {
+            Some(Value::Single(value)) => value,
+            other => panic!("single value expected, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn specs_parse_with_defaults() {
+        assert_eq!("sma".parse::<Spec>(), Ok(Spec::Sma(20)));
+        assert_eq!("ema:5".parse::<Spec>(), Ok(Spec::Ema(5)));
+        assert_eq!("macd".parse::<Spec>(), Ok(Spec::Macd { fast: 12, slow: 26, signal: 9 }));
+        assert_eq!("bollinger:10".parse::<Spec>(), Ok(Spec::Bollinger { period: 10, width: 2.0 }));
+        assert_eq!("bb:10:1.5".parse::<Spec>(), Ok(Spec::Bollinger { period: 10, width: 1.5 }));
+        assert_eq!("atr".parse::<Spec>(), Ok(Spec::Atr { period: 14, ticks: 10 }));
+        assert_eq!("macd:12:26:9".parse::<Spec>().unwrap().to_string(), "macd:12:26:9");
+        assert_eq!("atr:7".parse::<Spec>().unwrap().to_string(), "atr:7:10");
+    }
+
+    #[test]
+    fn bad_specs_are_rejected() {
+        for spec in ["sma:0", "sma:2.5", "sma:-3", "sma:1e15", "sma:10001", "ema:18446744073709551615",
+                     "rsi:14:2", "macd:26:12:9", "macd:12:26", "bb:20:0", "bb:20:inf", "atr:14:0", "vwap", ""] {
+            assert!(spec.parse::<Spec>().is_err(), "{} should be rejected", spec);
+        }
+        assert!("sma:10000".parse::<Spec>().is_ok());
+    }
+
+    #[test]
+    fn sma_averages_the_last_prices() {
+        let sma: Vec<_> = values("sma:3", &[1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(sma[..2], [None, None]);
+        assert_eq!(sma[2..].iter().map(|v| single(*v)).collect::<Vec<_>>(), [2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn ema_starts_from_the_simple_average() {
+        let ema = values("ema:3", &[1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(ema[1], None);
+        assert_eq!(ema[2..].iter().map(|v| single(*v)).collect::<Vec<_>>(), [2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn rsi_balances_gains_and_losses() {
+        let rsi = values("rsi:2", &[1.0, 2.0, 3.0, 2.0]);
+        assert_eq!(rsi[..2], [None, None]);
+        assert_eq!(single(rsi[2]), 100.0);
+        assert_eq!(single(rsi[3]), 50.0);
+        assert_eq!(single(values("rsi:2", &[5.0, 5.0, 5.0])[2]), 50.0);
+    }
+
+    #[test]
+    fn macd_of_a_steady_rise_is_constant() {
+        let macd = values("macd:2:3:2", &[1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(macd[..3], [None, None, None]);
+        for value in &macd[3..] {
+            assert_eq!(*value, Some(Value::Macd { macd: 0.5, signal: 0.5, histogram: 0.0 }));
+        }
+    }
+
+    #[test]
+    fn bollinger_bands_are_standard_deviations_apart() {
+        let Some(Value::Bands { lower, middle, upper }) = values("bb:3:2", &[1.0, 2.0, 3.0])[2] else {
+            panic!("bands expected");
+        };
+        let band = 2.0 * (2.0f64 / 3.0).sqrt();
+        assert_eq!(middle, 2.0);
+        assert!((upper - middle - band).abs() < 1e-12 && (middle - lower - band).abs() < 1e-12);
+    }
+
+    #[test]
+    fn atr_spans_the_previous_close() {
+        // bars of two trades: 10 12 then 11 9, whose true range reaches up to the close of 12
+        let atr = values("atr:2:2", &[10.0, 12.0, 11.0, 9.0, 10.0]);
+        assert_eq!(atr[..3], [None, None, None]);
+        assert_eq!(single(atr[3]), 2.5);
+        assert_eq!(single(atr[4]), 2.5);
+    }
+
+    #[test]
+    fn indicators_publish_once_warmed_up() {
+        let mut indicators = Indicators::default();
+        indicators.attach("sma:2".parse().unwrap()).unwrap();
+        indicators.attach("ema:3".parse().unwrap()).unwrap();
+        assert!(indicators.attach("sma:2".parse().unwrap()).is_err());
+        indicators.update(1.0);
+        indicators.update(3.0);
+        assert_eq!(indicators.values().into_iter().collect::<Vec<_>>(), [("sma:2".to_string(), Value::Single(2.0))]);
+        indicators.detach(&Spec::Sma(2)).unwrap();
+        assert!(indicators.values().is_empty());
+    }
+}
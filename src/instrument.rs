@@ -1,9 +1,29 @@
 // Import necessary modules from the standard library.
-use std::thread;
+use std::fs;
+use std::io;
+use std::path::Path;
 use std::mem::drop;
-use std::sync::RwLock;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
-use rand::{Rng}; // Import the Rng trait from the rand crate for random number generation.
+use rand::{Rng, RngCore, SeedableRng}; // Import the rand traits for seedable random number generation.
+use rand::rngs::StdRng;
+use rand_distr::{Distribution, Exp};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use crate::bars::{Bar, BarFile, BarSpec, Builder};
+use crate::capture::{self, Record, Recorder};
+use crate::clock::{self, Clock};
+use crate::history::{self, Tick};
+use crate::indicators::{self, Indicators};
+use crate::pattern::Pattern;
+use crate::price_model::{self, PriceProcess};
+use crate::protocol::{Condition, Depth, DepthChange, Level, Message, Prints, Quote};
+use crate::scheduler::{self, Scheduler};
+use crate::session::{Phase, Sessions};
+use crate::synthetic::Expr;
 
 #[path = "client.rs"] mod client;
 
@@ -16,6 +36,29 @@ pub enum Kind {
     Currency(String)
 }
 
+impl Kind {
+    // Build the kind of `symbol` from its name in a dictionary ("Equity", "Bond", "Warrant" or "Currency").
+    pub fn parse(kind: &str, symbol: String) -> Result<Kind, String> {
+        match kind {
+            "Equity" => Ok(Kind::Equity(symbol)),
+            "Bond" => Ok(Kind::Bond(symbol)),
+            "Warrant" => Ok(Kind::Warrant(symbol)),
+            "Currency" => Ok(Kind::Currency(symbol)),
+            _ => Err(format!("{} has an unknown kind '{}'", symbol, kind))
+        }
+    }
+
+    // Name of the kind, as written in a dictionary.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Equity(_) => "Equity",
+            Kind::Bond(_) => "Bond",
+            Kind::Warrant(_) => "Warrant",
+            Kind::Currency(_) => "Currency",
+        }
+    }
+}
+
 /*
 impl Iterator for Kind {
     type Item = Kind;
@@ -23,6 +66,15 @@ impl Iterator for Kind {
 }
 */
 
+// Define an enumeration of the trading status of an instrument in a data feed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Trading,  // Listed and updating.
+    Halted,   // Listed, but no update is published until trading resumes.
+    Delisted  // Removed from the data feed.
+}
+
 // Define a struct to hold data related to financial instruments.
 #[derive(Debug)]
 pub struct Data {
@@ -31,40 +83,160 @@ pub struct Data {
     ask: f64,    // The current ask price.
     open: f64,   // The opening price.
     close: f64,  // The closing price.
-    tick: usize  // A counter for the number of ticks (price changes).
+    tick: usize, // A counter for the number of ticks (price changes).
+    prints: Prints // The last trade and the volume, average price and number of trades of the session.
 }
 
 // Define a struct to hold financial instrument data with thread-safe read/write access.
 #[derive(Debug)]
 pub struct RwData {
-    rw: RwLock<Data> // Use a read-write lock to manage concurrent access to the data.
+    rw: RwLock<Data>, // Use a read-write lock to manage concurrent access to the data.
+    depth: RwLock<Depth> // The price levels of both sides, best price first.
 }
 
 // Define a struct to represent a financial instrument.
 #[derive(Debug)]
 pub struct Instrument {
     kind: Kind, // The kind of instrument (Equity, Bond, etc.).
+    description: String, // The full name of the instrument.
     data: RwData, // The data associated with the instrument.
-    subscribers: RwLock<usize> // A count of subscribers interested in updates for this instrument.
+    subscribers: RwLock<BTreeSet<SubscriberId>>, // The subscribers interested in updates for this instrument.
+    status: RwLock<Status>, // Whether the instrument trades, is halted or was delisted.
+    process: Mutex<Box<dyn PriceProcess>>, // The stochastic process generating the prices.
+    indicators: Mutex<Indicators> // The indicators computed on the trades, published with the prices.
 }
 
+// Reference close used when an instrument is not seeded from a dictionary.
+const DEFAULT_CLOSE: f64 = 100.0;
+// Daily volatility used when an instrument is not seeded from a dictionary.
+const DEFAULT_VOLATILITY: f64 = 0.04;
+// Number of levels on each side of the depth generated by the local feed.
+const DEPTH_LEVELS: i64 = 5;
+// Largest trade generated by the local feed, in lots of 100.
+const MAX_TRADE_LOTS: u64 = 10;
+
 // Implement methods for the Instrument struct.
 impl Instrument {
     // Constructor method to create a new Instrument instance.
     pub fn new(kind: Kind) -> Instrument {
+        let process = price_model::by_name(price_model::default_model(&kind), DEFAULT_CLOSE, DEFAULT_VOLATILITY).unwrap();
         Instrument {
             kind,
+            description: String::new(),
             data: RwData {
                 rw: RwLock::new(Data {
                     last: 0f64,
                     bid: 0f64,
                     ask: 0f64,
                     open: 0f64,
-                    close: 0f64,
-                    tick: 0
-                })
+                    close: DEFAULT_CLOSE,
+                    tick: 0,
+                    prints: Prints::default()
+                }),
+                depth: RwLock::new(Depth::default())
             },
-            subscribers: RwLock::new(0usize)
+            subscribers: RwLock::new(BTreeSet::new()),
+            status: RwLock::new(Status::Trading),
+            process: Mutex::new(process),
+            indicators: Mutex::new(Indicators::default())
+        }
+    }
+
+    // Method to set the full name of the instrument.
+    pub fn with_description(mut self, description: &str) -> Instrument {
+        self.description = description.to_string();
+        self
+    }
+
+    // Method to seed the price process from a reference close and a daily volatility.
+    // `model` selects the process ("gbm", "ou" or "jump"), the kind's default is used otherwise.
+    pub fn set_model(&self, close: f64, volatility: f64, model: Option<&str>) -> Result<(), String> {
+        let model = model.unwrap_or(price_model::default_model(&self.kind));
+        let process = price_model::by_name(model, close, volatility)?;
+        *self.process.lock().unwrap() = process;
+        let mut data = self.data.rw.write().unwrap();
+        data.close = close;
+        Ok(())
+    }
+
+    // Method to move the price one step of `dt` trading days along the price process, trading a random size
+    // with `condition`. The depth is rebuilt around the new price, one tick apart on each side, returning the levels that changed.
+    pub fn tick(&self, rng: &mut dyn RngCore, dt: f64, condition: Condition) -> Vec<DepthChange> {
+        let last = self.process.lock().unwrap().step(rng, dt);
+        let size = rng.gen_range(1..=MAX_TRADE_LOTS) * 100;
+        self.trade_at(rng, last, size, condition)
+    }
+
+    // Method to trade `size` at `last` from outside of the price process, like a historical bar.
+    // The depth is rebuilt around it as for a step, returning the levels that changed.
+    pub fn trade_at(&self, rng: &mut dyn RngCore, last: f64, size: u64, condition: Condition) -> Vec<DepthChange> {
+        let spread = last * price_model::half_spread(&self.kind);
+        let ticks_per_unit = (1.0 / price_model::tick_size(&self.kind)).round();
+        let best_bid = ((last - spread) * ticks_per_unit).floor() as i64;
+        let best_ask = (((last + spread) * ticks_per_unit).ceil() as i64).max(best_bid + 1);
+        let mut level = |ticks: i64| Level {
+            price: ticks as f64 / ticks_per_unit,
+            size: rng.gen_range(1..=10) * 100,
+            orders: rng.gen_range(1..=5),
+        };
+        let depth = Depth {
+            bids: (0..DEPTH_LEVELS).map(|i| level(best_bid - i)).collect(),
+            asks: (0..DEPTH_LEVELS).map(|i| level(best_ask + i)).collect(),
+        };
+
+        let mut data = self.data.rw.write().unwrap();
+        if data.tick == 0 {
+            data.open = last;
+        }
+        data.last = last;
+        data.bid = depth.bids[0].price;
+        data.ask = depth.asks[0].price;
+        data.tick += 1;
+        data.prints.trade(last, size, condition);
+        self.indicators.lock().unwrap().update(last);
+        let mut current = self.data.depth.write().unwrap();
+        let changes = current.changes(&depth);
+        *current = depth;
+        changes
+    }
+
+    // Method to record the last price as the print of an auction: the open of the session
+    // for the opening auction, its close for the closing auction.
+    pub fn auction_print(&self, auction: Phase) {
+        let mut data = self.data.rw.write().unwrap();
+        match auction {
+            Phase::OpeningAuction => data.open = data.last,
+            Phase::ClosingAuction => data.close = data.last,
+            _ => {}
+        }
+    }
+
+    // Method to overwrite the data with a quote received from the exchange,
+    // its indicators taking the last price when it traded.
+    pub fn apply(&self, quote: &Quote) {
+        let mut data = self.data.rw.write().unwrap();
+        if quote.prints.trades != data.prints.trades {
+            self.indicators.lock().unwrap().update(quote.last);
+        }
+        data.last = quote.last;
+        data.bid = quote.bid;
+        data.ask = quote.ask;
+        data.open = quote.open;
+        data.close = quote.close;
+        data.tick = quote.tick;
+        data.prints = quote.prints;
+    }
+
+    // Method to overwrite the depth with the full depth received from the exchange.
+    pub fn set_depth(&self, depth: Depth) {
+        *self.data.depth.write().unwrap() = depth;
+    }
+
+    // Method to apply the depth changes received from the exchange.
+    pub fn apply_depth(&self, changes: &[DepthChange]) {
+        let mut depth = self.data.depth.write().unwrap();
+        for change in changes {
+            depth.apply(change);
         }
     }
 
@@ -79,99 +251,1283 @@ impl Instrument {
         //&self.kind.take();
     }
 
-    // Method to get the number of subscribers.
-    pub fn get_subscribers(&self) -> usize {
+    // Method to set the prices of a synthetic instrument computed from its constituents.
+    // It has no depth, and each new price counts as a trade of no volume.
+    pub fn derive(&self, last: f64, bid: f64, ask: f64) {
+        let mut data = self.data.rw.write().unwrap();
+        if data.tick == 0 {
+            data.open = last;
+        }
+        data.last = last;
+        data.bid = bid;
+        data.ask = ask;
+        data.tick += 1;
+        data.prints.trade(last, 0, Condition::Regular);
+        self.indicators.lock().unwrap().update(last);
+    }
+
+    // Method to attach a streaming indicator, computed on the trades from now on.
+    pub fn attach(&self, spec: indicators::Spec) -> Result<(), String> {
+        self.indicators.lock().unwrap().attach(spec)
+    }
+
+    // Method to detach an indicator.
+    pub fn detach(&self, spec: &indicators::Spec) -> Result<(), String> {
+        self.indicators.lock().unwrap().detach(spec)
+    }
+
+    // Method to get the values of the indicators by name, those still warming up are left out.
+    pub fn indicators(&self) -> BTreeMap<String, indicators::Value> {
+        self.indicators.lock().unwrap().values()
+    }
+
+    // Method to get the full name of the instrument.
+    pub fn get_description(&self) -> &str {
+        &self.description
+    }
+
+    // Method to get the kind of the instrument.
+    pub fn get_kind(&self) -> &Kind {
+        &self.kind
+    }
+
+    // Method to get the identifiers of the subscribers.
+    pub fn get_subscriber_ids(&self) -> Vec<SubscriberId> {
         let s = self.subscribers.read().unwrap();
-        *s
+        s.iter().copied().collect()
     }
 
-    // Method to simulate sending an image update to subscribers.
-    pub fn on_image(&self) {
-        println!("Image for {} {:?}", &self.get_name(), &self);
+    // Method to get the trading status of the instrument.
+    pub fn status(&self) -> Status {
+        *self.status.read().unwrap()
     }
 
-    // Method to simulate sending a data update to subscribers.
-    pub fn on_update(&self) {
+    // Method to get the current prices of the instrument.
+    pub fn quote(&self) -> Quote {
         let data = self.data.rw.read().unwrap();
-        eprintln!("Update for {} {:?}", &self.get_name(), *data);
-        drop(data); // Explicitly drop the read lock to release it.
+        Quote {
+            last: data.last,
+            bid: data.bid,
+            ask: data.ask,
+            open: data.open,
+            close: data.close,
+            tick: data.tick,
+            prints: data.prints,
+        }
+    }
+
+    // Method to build the image event of the instrument, with its full depth.
+    pub fn on_image(&self) -> Event {
+        Event::Image {
+            symbol: self.get_name().to_string(),
+            quote: self.quote(),
+            depth: self.data.depth.read().unwrap().clone(),
+            indicators: self.indicators(),
+        }
+    }
+
+    // Method to build the update event of the instrument, with the depth changes.
+    pub fn on_update(&self, changes: &[DepthChange]) -> Event {
+        Event::Update {
+            symbol: self.get_name().to_string(),
+            quote: self.quote(),
+            depth: changes.to_vec(),
+            indicators: self.indicators(),
+        }
     }
 }
 
+// Define an enumeration of the typed events delivered to the subscribers of a data feed,
+// serialized like the messages of the exchange protocol in the capture files.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    // The full image of an instrument, sent when subscribing and when the source resends it.
+    Image {
+        symbol: String,
+        quote: Quote,
+        #[serde(default)]
+        depth: Depth,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        indicators: BTreeMap<String, indicators::Value>,
+    },
+    // The new prices of an instrument and the levels of its depth that changed.
+    Update {
+        symbol: String,
+        quote: Quote,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        depth: Vec<DepthChange>,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        indicators: BTreeMap<String, indicators::Value>,
+    },
+    // The constituents of a chain, sent when subscribing to it.
+    ChainImage { chain: String, constituents: Vec<String> },
+    // The symbols that joined or left a chain.
+    ChainUpdate { chain: String, added: Vec<String>, removed: Vec<String> },
+    // The new trading status of an instrument: halted, trading again or delisted.
+    Status { symbol: String, status: Status },
+    // A completed bar of an instrument, sent to the subscribers of its bars only.
+    Bar { symbol: String, spec: BarSpec, bar: Bar },
+}
+
+// Define an enumeration of the ways a subscriber receives its events.
+pub enum Sink {
+    Callback(Box<dyn Fn(&Event) + Send + Sync>), // Called on the thread running the feed.
+    Channel(mpsc::Sender<Event>),
+    Broadcast(broadcast::Sender<Event>), // Shared by any number of tokio receivers.
+}
+
+impl Sink {
+    // Constructor method for a callback sink.
+    pub fn callback<F: Fn(&Event) + Send + Sync + 'static>(callback: F) -> Sink {
+        Sink::Callback(Box::new(callback))
+    }
+
+    // Constructor method for a channel sink and the receiver of its events.
+    pub fn channel() -> (Sink, mpsc::Receiver<Event>) {
+        let (sender, receiver) = mpsc::channel();
+        (Sink::Channel(sender), receiver)
+    }
+
+    // Constructor method for a broadcast sink keeping up to `capacity` events for slow receivers.
+    pub fn broadcast(capacity: usize) -> (Sink, broadcast::Receiver<Event>) {
+        let (sender, receiver) = broadcast::channel(capacity);
+        (Sink::Broadcast(sender), receiver)
+    }
+
+    // Method to deliver an event, a closed channel is ignored.
+    fn deliver(&self, event: &Event) {
+        match self {
+            Sink::Callback(callback) => callback(event),
+            Sink::Channel(sender) => {
+                let _ = sender.send(event.clone());
+            }
+            Sink::Broadcast(sender) => {
+                let _ = sender.send(event.clone());
+            }
+        }
+    }
+}
+
+// Define a struct for a chain record: a named list of constituents like an index membership
+// or a bond curve, in the order of the list.
+#[derive(Debug, Default)]
+struct Chain {
+    constituents: Vec<String>,
+    subscribers: BTreeSet<SubscriberId>,
+}
+
+// Define a struct for the bars of an instrument, built for their subscribers and a bar file.
+struct BarSeries {
+    builder: Builder,
+    subscribers: BTreeSet<SubscriberId>,
+    file: Option<BarFile>,
+    prints: Prints, // The trades of the session at the last update.
+}
+
+impl BarSeries {
+    fn new(spec: BarSpec) -> BarSeries {
+        BarSeries { builder: Builder::new(spec), subscribers: BTreeSet::new(), file: None, prints: Prints::default() }
+    }
+
+    // Method to get the volume traded since the last update, `None` when nothing traded.
+    fn traded(&mut self, prints: &Prints) -> Option<u64> {
+        let previous = std::mem::replace(&mut self.prints, *prints);
+        match prints.trades {
+            trades if trades == previous.trades => None,
+            trades if trades < previous.trades => Some(prints.volume), // a new session
+            _ => Some(prints.volume.saturating_sub(previous.volume))
+        }
+    }
+
+    // Method to write a completed bar to the file, the file is dropped on the first error.
+    fn write(&mut self, symbol: &str, bar: &Bar) {
+        if let Some(file) = self.file.as_mut() {
+            if let Err(e) = file.write(bar).and_then(|_| file.flush()) {
+                println!("ERROR::bars of {}::{}", symbol, e);
+                self.file = None;
+            }
+        }
+    }
+}
+
+// Define a struct for a client connected to a data feed.
+struct Client {
+    name: String,
+    sink: Sink,
+}
+
 // Import the HashMap collection from the standard library.
 use std::collections::HashMap;
 
+// Identifier of a client of a data feed.
+pub type SubscriberId = usize;
+
 // Define a struct to manage a collection of instruments and their updates.
-pub struct DataFeed<'a> {
-    registry: HashMap<&'a String, &'a Instrument>, // A registry mapping instrument names to their references.
+// The instruments are shared with the running feed, so they can be listed, delisted,
+// halted and resumed from another thread while it runs.
+pub struct DataFeed {
+    registry: RwLock<HashMap<String, Arc<Instrument>>>, // A registry mapping instrument names to the listed instruments.
+    listings: AtomicUsize, // The number of instruments listed so far, for the running feed to notice new ones.
     name: String, // The name of the data feed.
+    clients: RwLock<HashMap<SubscriberId, Arc<Client>>>, // The connected subscribers.
+    patterns: RwLock<Vec<(SubscriberId, Pattern)>>, // The pattern subscriptions, also applied to instruments added later.
+    chains: RwLock<BTreeMap<String, Chain>>, // The chain records by name.
+    next_subscriber: AtomicUsize, // The identifier of the next subscriber.
+    recorder: Mutex<Option<Recorder>>, // The capture file of the images and updates, when recording.
+    bars: Mutex<BTreeMap<(String, BarSpec), BarSeries>>, // The bars built by symbol and specification.
+    synthetics: RwLock<BTreeMap<String, Expr>>, // The expressions pricing the synthetic instruments, by symbol.
+    clock: Arc<Clock>, // The clock timing the bars.
+}
+
+// Handle of a client connected to a data feed, its subscriptions end when it is dropped.
+pub struct Subscriber<'f> {
+    id: SubscriberId,
+    feed: &'f DataFeed,
+}
+
+impl Subscriber<'_> {
+    // Method to subscribe to an instrument by name and receive its image then its updates.
+    pub fn subscribe(&self, name: &str) -> Result<Arc<Instrument>, String> {
+        self.feed.subscribe(self.id, name)
+    }
+
+    // Method to stop receiving the updates of an instrument.
+    pub fn unsubscribe(&self, name: &str) -> Result<(), String> {
+        self.feed.unsubscribe(self.id, name)
+    }
+
+    // Method to subscribe to every instrument matching a pattern, now or once added.
+    pub fn subscribe_pattern(&self, pattern: Pattern) -> Vec<Arc<Instrument>> {
+        self.feed.subscribe_pattern(self.id, pattern)
+    }
+
+    // Method to drop a pattern subscription and the subscriptions it made.
+    pub fn unsubscribe_pattern(&self, pattern: &str) -> Result<(), String> {
+        self.feed.unsubscribe_pattern(self.id, pattern)
+    }
+
+    // Method to subscribe to the bars of an instrument, receiving each bar as it completes.
+    pub fn subscribe_bars(&self, name: &str, spec: BarSpec) -> Result<(), String> {
+        self.feed.subscribe_bars(self.id, name, spec)
+    }
+
+    // Method to stop receiving the bars of an instrument.
+    pub fn unsubscribe_bars(&self, name: &str, spec: BarSpec) -> Result<(), String> {
+        self.feed.unsubscribe_bars(self.id, name, spec)
+    }
+
+    // Method to subscribe to a chain, receiving its constituents then its membership changes.
+    pub fn subscribe_chain(&self, name: &str) -> Result<Vec<String>, String> {
+        self.feed.subscribe_chain(self.id, name)
+    }
+
+    // Method to stop receiving the membership changes of a chain.
+    pub fn unsubscribe_chain(&self, name: &str) -> Result<(), String> {
+        self.feed.unsubscribe_chain(self.id, name)
+    }
+}
+
+impl Drop for Subscriber<'_> {
+    fn drop(&mut self) {
+        self.feed.disconnect(self.id);
+    }
+}
+
+// State of an instrument updated by the local feed.
+struct Slot {
+    instrument: Arc<Instrument>,
+    rng: StdRng, // The generator of the instrument, seeded from the feed seed and its name.
+    previous: Duration, // The time of the last update, or of the last phase change outside of trading.
+    remaining: usize, // The number of updates left.
+    phase: Phase, // The trading phase at the last event.
 }
 
 // Implement methods for the DataFeed struct.
-impl<'a> DataFeed<'a> {
+impl DataFeed {
     // Constructor method to create a new DataFeed instance.
-    pub fn new(name: String) -> DataFeed<'a> {
+    pub fn new(name: String) -> DataFeed {
         DataFeed {
             name,
-            registry: HashMap::new(),
+            registry: RwLock::new(HashMap::new()),
+            listings: AtomicUsize::new(0),
+            clients: RwLock::new(HashMap::new()),
+            patterns: RwLock::new(Vec::new()),
+            chains: RwLock::new(BTreeMap::new()),
+            next_subscriber: AtomicUsize::new(0),
+            recorder: Mutex::new(None),
+            bars: Mutex::new(BTreeMap::new()),
+            synthetics: RwLock::new(BTreeMap::new()),
+            clock: Arc::new(Clock::new(clock::Mode::Real)),
         }
     }
 
-    // Method to add an instrument to the registry.
-    pub async fn add(&mut self, i: &'a Instrument) {
-        self.registry.insert(i.get_name(), i);
-        client::request("127.0.0.1", 7878, i.get_name());
+    // Method to time the bars with the clock of the simulation rather than the wall clock.
+    pub fn with_clock(mut self, clock: Arc<Clock>) -> DataFeed {
+        self.clock = clock;
+        self
+    }
+
+    // Method to list an instrument, subscribing the clients whose patterns match it, and recording its image.
+    // It may be called while the feed runs, the instrument then starts updating at once.
+    pub fn add(&self, i: Instrument) -> Result<Arc<Instrument>, String> {
+        let i = Arc::new(i);
+        let mut registry = self.registry.write().unwrap();
+        if registry.contains_key(i.get_name()) {
+            return Err(format!("{} instrument already listed", i.get_name()));
+        }
+        registry.insert(i.get_name().to_string(), Arc::clone(&i));
+        drop(registry); // Explicitly drop the write lock to release it.
+        self.listings.fetch_add(1, Ordering::SeqCst);
+        self.capture(&i.on_image());
+        let subscribers: Vec<SubscriberId> = self.patterns.read().unwrap().iter()
+            .filter(|(_, pattern)| pattern.matches(&i))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in subscribers {
+            let _ = self.subscribe(id, i.get_name());
+        }
+        Ok(i)
+    }
+
+    // Method to list a synthetic instrument priced by `expression` over instruments already listed,
+    // recomputed whenever one of them updates. Its close is computed from their closes.
+    pub fn add_synthetic(&self, i: Instrument, expression: Expr) -> Result<Arc<Instrument>, String> {
+        let name = i.get_name().to_string();
+        if self.get(&name).is_some() {
+            return Err(format!("{} instrument already listed", name));
+        }
+        if let Some(missing) = expression.symbols().into_iter().find(|symbol| self.get(symbol).is_none()) {
+            return Err(format!("{} instrument not found for {}", missing, name));
+        }
+        let close = expression.value(&|symbol| self.get(symbol).map(|c| c.quote().close))
+            .ok_or(format!("{} cannot be computed from the closes of {}", name, expression))?;
+        i.data.rw.write().unwrap().close = close;
+        // known as synthetic before it is listed, so that no source prices it
+        self.synthetics.write().unwrap().insert(name.to_string(), expression);
+        self.add(i).inspect_err(|_| {
+            self.synthetics.write().unwrap().remove(&name);
+        })
+    }
+
+    fn is_synthetic(&self, name: &str) -> bool {
+        self.synthetics.read().unwrap().contains_key(name)
+    }
+
+    fn has_synthetics(&self, constituent: &str) -> bool {
+        self.synthetics.read().unwrap().values().any(|expression| expression.symbols().contains(constituent))
+    }
+
+    // Method to get the instruments priced by the source of the feed, in symbol order,
+    // the synthetic instruments being priced from them.
+    fn sourced_instruments(&self) -> Vec<Arc<Instrument>> {
+        self.instruments().into_iter().filter(|i| !self.is_synthetic(i.get_name())).collect()
+    }
+
+    // Method to price again the synthetic instruments using `constituent` and publish their updates.
+    // The last price of a constituent that did not trade yet is its close, its bid and ask are its last
+    // price until it quotes both sides.
+    fn derive_synthetics(&self, constituent: &str) {
+        let dependents: Vec<(Arc<Instrument>, Expr)> = self.synthetics.read().unwrap().iter()
+            .filter(|(_, expression)| expression.symbols().contains(constituent))
+            .filter_map(|(name, expression)| self.get(name).map(|i| (i, expression.clone())))
+            .collect();
+        for (i, expression) in dependents {
+            let last = |symbol: &str| self.get(symbol).map(|c| c.quote()).map(|q| if q.tick > 0 { q.last } else { q.close });
+            let sides = |symbol: &str| {
+                let q = self.get(symbol)?.quote();
+                if q.bid > 0.0 && q.ask > 0.0 { Some((q.bid, q.ask)) } else { last(symbol).map(|last| (last, last)) }
+            };
+            if let (Some(last), Some((bid, ask))) = (expression.value(&last), expression.bounds(&sides)) {
+                i.derive(last, bid, ask);
+                self.send_update(&i, &[]);
+            }
+        }
+    }
+
+    // Method to delist an instrument: its subscribers receive the delisted status, then it
+    // leaves the chains it belonged to. The pattern subscriptions still apply if it is listed again.
+    pub fn remove(&self, name: &str) -> Result<Arc<Instrument>, String> {
+        let i = self.registry.write().unwrap().remove(name).ok_or(format!("{} instrument not found", name))?;
+        self.synthetics.write().unwrap().remove(name);
+        *i.status.write().unwrap() = Status::Delisted;
+        self.send(&i, Event::Status { symbol: name.to_string(), status: Status::Delisted });
+        i.subscribers.write().unwrap().clear();
+        let chains: Vec<String> = self.chains.read().unwrap().iter()
+            .filter(|(_, chain)| chain.constituents.iter().any(|c| c == name))
+            .map(|(chain, _)| chain.to_string())
+            .collect();
+        for chain in chains {
+            let _ = self.remove_constituent(&chain, name);
+        }
+        Ok(i)
+    }
+
+    // Method to halt the trading of an instrument, no update is published until it resumes.
+    pub fn halt(&self, name: &str) -> Result<(), String> {
+        let i = self.change_status(name, Status::Trading, Status::Halted)?;
+        self.send(&i, Event::Status { symbol: name.to_string(), status: Status::Halted });
+        Ok(())
+    }
+
+    // Method to resume the trading of a halted instrument, its subscribers receive a fresh image.
+    pub fn resume(&self, name: &str) -> Result<(), String> {
+        let i = self.change_status(name, Status::Halted, Status::Trading)?;
+        self.send(&i, Event::Status { symbol: name.to_string(), status: Status::Trading });
+        self.send_image(&i);
+        Ok(())
+    }
+
+    // Method to move an instrument from the status `from` to the status `to`.
+    fn change_status(&self, name: &str, from: Status, to: Status) -> Result<Arc<Instrument>, String> {
+        let i = self.get(name).ok_or(format!("{} instrument not found", name))?;
+        let mut status = i.status.write().unwrap();
+        if *status != from {
+            return Err(format!("{} is {:?}, not {:?}", name, *status, from));
+        }
+        *status = to;
+        drop(status); // Explicitly drop the write lock to release it.
+        Ok(i)
+    }
+
+    // Method to get a listed instrument by name.
+    pub fn get(&self, name: &str) -> Option<Arc<Instrument>> {
+        self.registry.read().unwrap().get(name).cloned()
+    }
+
+    // Method to get the listed instruments, in symbol order.
+    // The registry lock is not held afterwards, so that the feed may change while they are used.
+    pub fn instruments(&self) -> Vec<Arc<Instrument>> {
+        let mut instruments: Vec<Arc<Instrument>> = self.registry.read().unwrap().values().cloned().collect();
+        instruments.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+        instruments
     }
 
     // Method to simulate sending image updates to all subscribed instruments.
+    #[allow(dead_code)]
     pub fn flush(&self) {
-        for (_, v) in &self.registry {
-            if v.get_subscribers() > 0 {
-                v.on_image();
+        for v in self.instruments() {
+            self.send_image(&v);
+        }
+    }
+
+    // Method to start recording the images, updates and status changes of the instruments to a
+    // capture file, stamped with the time of `clock`; the file starts with the image of every instrument.
+    pub fn record(&self, path: &str, clock: Arc<Clock>) -> Result<(), String> {
+        let mut recorder = Recorder::create(path, clock)?;
+        for i in self.instruments() {
+            recorder.write(&i.on_image()).map_err(|e| format!("capture {}::{}", path, e))?;
+        }
+        println!("recording {} to {}", self.name, path);
+        *self.recorder.lock().unwrap() = Some(recorder);
+        Ok(())
+    }
+
+    // Method to stop recording, writing out what remains of the capture file.
+    pub fn stop_recording(&self) {
+        if let Some(mut recorder) = self.recorder.lock().unwrap().take() {
+            if let Err(e) = recorder.flush() {
+                println!("ERROR::capture::{}", e);
             }
         }
     }
 
-    // Method to subscribe to an instrument by name and receive updates.
-    pub fn subscribe(&mut self, name: String) -> Result<&Instrument, String> {
-        match self.registry.get(&name) {
-            Some(&instrument) => {
+    fn recording(&self) -> bool {
+        self.recorder.lock().unwrap().is_some()
+    }
+
+    // Method to write an event to the capture file, recording stops on the first error.
+    fn capture(&self, event: &Event) {
+        let mut recorder = self.recorder.lock().unwrap();
+        if let Some(r) = recorder.as_mut() {
+            if let Err(e) = r.write(event) {
+                println!("ERROR::capture::{}", e);
+                *recorder = None;
+            }
+        }
+    }
+
+    // Method to connect a new client receiving its events through `sink`.
+    pub fn connect(&self, name: &str, sink: Sink) -> Subscriber<'_> {
+        let id = self.next_subscriber.fetch_add(1, Ordering::SeqCst);
+        self.clients.write().unwrap().insert(id, Arc::new(Client { name: name.to_string(), sink }));
+        Subscriber { id, feed: self }
+    }
+
+    // Method to drop all the subscriptions of a client.
+    pub fn disconnect(&self, subscriber: SubscriberId) {
+        if let Some(client) = self.clients.write().unwrap().remove(&subscriber) {
+            println!("disconnected {}", client.name);
+        }
+        self.patterns.write().unwrap().retain(|(id, _)| *id != subscriber);
+        for chain in self.chains.write().unwrap().values_mut() {
+            chain.subscribers.remove(&subscriber);
+        }
+        for i in self.instruments() {
+            i.subscribers.write().unwrap().remove(&subscriber);
+        }
+        self.bars.lock().unwrap().retain(|_, series| {
+            series.subscribers.remove(&subscriber);
+            !series.subscribers.is_empty() || series.file.is_some()
+        });
+    }
+
+    // Method to subscribe a client to an instrument by name, sending it the image of the instrument,
+    // followed by its status when it is halted.
+    pub fn subscribe(&self, subscriber: SubscriberId, name: &str) -> Result<Arc<Instrument>, String> {
+        let Some(client) = self.client(subscriber) else {
+            return Err(format!("subscriber {} not connected", subscriber));
+        };
+        match self.get(name) {
+            Some(instrument) => {
                 let mut s = instrument.subscribers.write().unwrap();
-                *s += 1;
+                s.insert(subscriber);
                 drop(s); // Explicitly drop the write lock to release it.
-                instrument.on_image();
-                Ok(&instrument)
+                client.sink.deliver(&instrument.on_image());
+                if instrument.status() == Status::Halted {
+                    client.sink.deliver(&Event::Status { symbol: name.to_string(), status: Status::Halted });
+                }
+                Ok(instrument)
             }
             _ => Err(format!("{} instrument not found", name))
         }
     }
 
+    // Method to unsubscribe a client from an instrument.
+    pub fn unsubscribe(&self, subscriber: SubscriberId, name: &str) -> Result<(), String> {
+        let instrument = self.get(name).ok_or(format!("{} instrument not found", name))?;
+        if instrument.subscribers.write().unwrap().remove(&subscriber) {
+            Ok(())
+        } else {
+            Err(format!("{} not subscribed to {}", subscriber, name))
+        }
+    }
+
+    // Method to subscribe a client to every instrument matching `pattern`, now or once added.
+    // Returns the instruments matching it so far, in symbol order.
+    pub fn subscribe_pattern(&self, subscriber: SubscriberId, pattern: Pattern) -> Vec<Arc<Instrument>> {
+        if self.client(subscriber).is_none() {
+            return Vec::new();
+        }
+        let matching: Vec<Arc<Instrument>> = self.instruments().into_iter().filter(|i| pattern.matches(i)).collect();
+        self.patterns.write().unwrap().push((subscriber, pattern));
+        matching.into_iter().filter_map(|i| self.subscribe(subscriber, i.get_name()).ok()).collect()
+    }
+
+    // Method to drop a pattern subscription of a client and unsubscribe it from the instruments it matches.
+    pub fn unsubscribe_pattern(&self, subscriber: SubscriberId, pattern: &str) -> Result<(), String> {
+        let mut patterns = self.patterns.write().unwrap();
+        let position = patterns.iter().position(|(id, p)| *id == subscriber && p.text() == pattern)
+            .ok_or(format!("{} not subscribed to {}", subscriber, pattern))?;
+        let (_, pattern) = patterns.remove(position);
+        drop(patterns); // Explicitly drop the write lock to release it.
+        for i in self.instruments().into_iter().filter(|i| pattern.matches(i)) {
+            let _ = self.unsubscribe(subscriber, i.get_name());
+        }
+        Ok(())
+    }
+
+    // Method to subscribe a client to the bars of an instrument, sending it each bar as it completes.
+    pub fn subscribe_bars(&self, subscriber: SubscriberId, name: &str, spec: BarSpec) -> Result<(), String> {
+        if self.client(subscriber).is_none() {
+            return Err(format!("subscriber {} not connected", subscriber));
+        }
+        self.get(name).ok_or(format!("{} instrument not found", name))?;
+        let mut bars = self.bars.lock().unwrap();
+        let series = bars.entry((name.to_string(), spec)).or_insert_with(|| BarSeries::new(spec));
+        series.subscribers.insert(subscriber);
+        Ok(())
+    }
+
+    // Method to unsubscribe a client from the bars of an instrument, they are no longer built
+    // once nobody receives them.
+    pub fn unsubscribe_bars(&self, subscriber: SubscriberId, name: &str, spec: BarSpec) -> Result<(), String> {
+        let mut bars = self.bars.lock().unwrap();
+        let key = (name.to_string(), spec);
+        let series = bars.get_mut(&key).filter(|series| series.subscribers.contains(&subscriber))
+            .ok_or(format!("{} not subscribed to the {} bars of {}", subscriber, spec, name))?;
+        series.subscribers.remove(&subscriber);
+        if series.subscribers.is_empty() && series.file.is_none() {
+            bars.remove(&key);
+        }
+        Ok(())
+    }
+
+    // Method to write the bars of every instrument listed so far to `<dir>/<symbol>.<spec>.csv`.
+    pub fn write_bars(&self, dir: &str, spec: BarSpec) -> Result<(), String> {
+        fs::create_dir_all(dir).map_err(|e| format!("creating {}::{}", dir, e))?;
+        for i in self.instruments() {
+            let file = BarFile::create(&Path::new(dir).join(format!("{}.{}.csv", i.get_name(), spec)))?;
+            let mut bars = self.bars.lock().unwrap();
+            bars.entry((i.get_name().to_string(), spec)).or_insert_with(|| BarSeries::new(spec)).file = Some(file);
+        }
+        println!("writing the {} bars of {} to {}", spec, self.name, dir);
+        Ok(())
+    }
+
+    // Method to complete the bars in progress, sending them and writing them out.
+    pub fn finish_bars(&self) {
+        let mut completed = Vec::new();
+        for ((symbol, spec), series) in self.bars.lock().unwrap().iter_mut() {
+            if let Some(bar) = series.builder.finish() {
+                series.write(symbol, &bar);
+                completed.push((series.subscribers.clone(), Event::Bar { symbol: symbol.to_string(), spec: *spec, bar }));
+            }
+        }
+        self.deliver_bars(completed);
+    }
+
+    fn building_bars(&self, symbol: &str) -> bool {
+        self.bars.lock().unwrap().keys().any(|(s, _)| s == symbol)
+    }
+
+    // Method to add the trades of an update of an instrument to its bars, sending and writing the bars it completes.
+    // An update without trades leaves the bars as they are.
+    fn build_bars(&self, symbol: &str, quote: &Quote) {
+        let time = self.clock.now().as_secs_f64();
+        let mut completed = Vec::new();
+        for ((s, spec), series) in self.bars.lock().unwrap().iter_mut().filter(|((s, _), _)| s == symbol) {
+            let Some(volume) = series.traded(&quote.prints) else {
+                continue;
+            };
+            if let Some(bar) = series.builder.update(time, quote.last, volume) {
+                series.write(s, &bar);
+                completed.push((series.subscribers.clone(), Event::Bar { symbol: s.to_string(), spec: *spec, bar }));
+            }
+        }
+        self.deliver_bars(completed);
+    }
+
+    // The bar lock is not held while delivering, so that a callback may use the feed.
+    fn deliver_bars(&self, completed: Vec<(BTreeSet<SubscriberId>, Event)>) {
+        for (subscribers, event) in completed {
+            for client in subscribers.into_iter().filter_map(|id| self.client(id)) {
+                client.sink.deliver(&event);
+            }
+        }
+    }
+
+    // Method to add a chain record, or to replace the constituents of an existing one.
+    pub fn add_chain(&self, name: &str, constituents: Vec<String>) {
+        let _ = self.change_chain(name, true, |c| {
+            *c = constituents;
+            Ok(())
+        });
+    }
+
+    // Method to get the constituents of a chain.
+    pub fn get_chain(&self, name: &str) -> Option<Vec<String>> {
+        self.chains.read().unwrap().get(name).map(|c| c.constituents.clone())
+    }
+
+    // Method to append a symbol to the constituents of a chain.
+    pub fn add_constituent(&self, name: &str, symbol: &str) -> Result<(), String> {
+        self.change_chain(name, false, |c| {
+            if c.iter().any(|s| s == symbol) {
+                return Err(format!("{} already in {}", symbol, name));
+            }
+            c.push(symbol.to_string());
+            Ok(())
+        })
+    }
+
+    // Method to remove a symbol from the constituents of a chain.
+    pub fn remove_constituent(&self, name: &str, symbol: &str) -> Result<(), String> {
+        self.change_chain(name, false, |c| {
+            let position = c.iter().position(|s| s == symbol).ok_or(format!("{} not in {}", symbol, name))?;
+            c.remove(position);
+            Ok(())
+        })
+    }
+
+    // Method to change the constituents of a chain, created when `create`, and send the
+    // membership changes to its subscribers.
+    fn change_chain<F>(&self, name: &str, create: bool, change: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut Vec<String>) -> Result<(), String>
+    {
+        let mut chains = self.chains.write().unwrap();
+        let chain = match chains.get_mut(name) {
+            Some(chain) => chain,
+            None if create => chains.entry(name.to_string()).or_default(),
+            None => return Err(format!("{} chain not found", name))
+        };
+        let before = chain.constituents.clone();
+        change(&mut chain.constituents)?;
+        let added: Vec<String> = chain.constituents.iter().filter(|c| !before.contains(c)).cloned().collect();
+        let removed: Vec<String> = before.into_iter().filter(|c| !chain.constituents.contains(c)).collect();
+        let subscribers: Vec<SubscriberId> = chain.subscribers.iter().copied().collect();
+        drop(chains); // Explicitly drop the write lock to release it.
+        if added.is_empty() && removed.is_empty() {
+            return Ok(());
+        }
+        let event = Event::ChainUpdate { chain: name.to_string(), added, removed };
+        for client in subscribers.into_iter().filter_map(|id| self.client(id)) {
+            client.sink.deliver(&event);
+        }
+        Ok(())
+    }
+
+    // Method to subscribe a client to a chain, sending it the constituents.
+    pub fn subscribe_chain(&self, subscriber: SubscriberId, name: &str) -> Result<Vec<String>, String> {
+        let client = self.client(subscriber).ok_or(format!("subscriber {} not connected", subscriber))?;
+        let mut chains = self.chains.write().unwrap();
+        let chain = chains.get_mut(name).ok_or(format!("{} chain not found", name))?;
+        chain.subscribers.insert(subscriber);
+        let constituents = chain.constituents.clone();
+        drop(chains); // Explicitly drop the write lock to release it.
+        client.sink.deliver(&Event::ChainImage { chain: name.to_string(), constituents: constituents.clone() });
+        Ok(constituents)
+    }
+
+    // Method to unsubscribe a client from a chain.
+    pub fn unsubscribe_chain(&self, subscriber: SubscriberId, name: &str) -> Result<(), String> {
+        let mut chains = self.chains.write().unwrap();
+        let chain = chains.get_mut(name).ok_or(format!("{} chain not found", name))?;
+        if chain.subscribers.remove(&subscriber) {
+            Ok(())
+        } else {
+            Err(format!("{} not subscribed to {}", subscriber, name))
+        }
+    }
+
+    // The client lock is not held while delivering, so that a callback may use the feed.
+    fn client(&self, subscriber: SubscriberId) -> Option<Arc<Client>> {
+        self.clients.read().unwrap().get(&subscriber).cloned()
+    }
+
+    // Method to send an event of an instrument to each of its subscribers, and to the capture file.
+    // An update also goes into the bars of the instrument.
+    fn send(&self, i: &Instrument, event: Event) {
+        self.capture(&event);
+        for client in i.get_subscriber_ids().into_iter().filter_map(|id| self.client(id)) {
+            client.sink.deliver(&event);
+        }
+        if let Event::Update { symbol, quote, .. } = &event {
+            self.build_bars(symbol, quote);
+            self.derive_synthetics(symbol);
+        }
+    }
+
+    // True when the events of an instrument go anywhere: it trades and has subscribers, is recorded,
+    // builds bars or prices synthetic instruments.
+    fn publishing(&self, i: &Instrument) -> bool {
+        i.status() == Status::Trading
+            && (!i.get_subscriber_ids().is_empty() || self.recording() || self.building_bars(i.get_name())
+                || self.has_synthetics(i.get_name()))
+    }
+
+    // Method to send the image of an instrument to each of its subscribers, unless it is halted.
+    fn send_image(&self, i: &Instrument) {
+        if !self.publishing(i) {
+            return;
+        }
+        self.send(i, i.on_image());
+    }
+
+    // Method to send an update of an instrument to each of its subscribers, unless it is halted.
+    fn send_update(&self, i: &Instrument, changes: &[DepthChange]) {
+        if !self.publishing(i) {
+            return;
+        }
+        self.send(i, i.on_update(changes));
+    }
+
     // Method to start the data feed and simulate instrument updates.
-    pub fn start(&self, loops:usize) {
-        thread::scope(|scope| {
-            for (k, i) in &self.registry {
-                scope.spawn(move || {
-                    println!("Starting {}", k);
-                    let mut r = rand::thread_rng();
-                    for _ in 1..loops {
-                        let ms = r.gen_range(0..1000);
-                        thread::sleep(Duration::from_millis(ms));
-                        let mut tmp = i.data.rw.write().unwrap();
-                        (*tmp).last = ms as f64;
-                        (*tmp).tick += 1;
-                        drop(tmp); // Explicitly drop the write lock to release it.
-                        let s = i.subscribers.read().unwrap();
-                        let subscribers = *s;
-                        drop(s); // Explicitly drop the read lock to release it.
-                        if subscribers > 0 {
-                            i.on_update();
-                        }
+    // Every instrument draws from its own generator seeded from `seed` and its name,
+    // and a single scheduler orders the updates, so a given seed always replays the same sequence.
+    // Updates arrive as a Poisson process of `rate` updates per second of `clock` time over the feed,
+    // shared by the instruments listed at the start; an instrument listed later updates at the same pace.
+    // A halted instrument lets its updates pass, its price catching up when it resumes,
+    // and a delisted one stops updating.
+    // With `sessions`, an instrument updates in continuous trading only: its price moves during the
+    // auctions and their print sets the open or the close, it waits for the next phase change otherwise.
+    pub fn start(&self, loops:usize, seed: u64, rate: f64, clock: &Clock, sessions: Option<&Sessions>) {
+        println!("Starting feed {} with seed {}", self.name, seed);
+        let instruments = self.sourced_instruments();
+        if instruments.is_empty() || rate <= 0.0 {
+            println!("finished, nothing to update at rate {}", rate);
+            return;
+        }
+        let interval = Exp::new(rate / instruments.len() as f64).unwrap();
+        let phase = |i: &Instrument, at: Duration| sessions.map_or(Phase::Continuous, |s| s.phase(i.get_kind(), at));
+        // the time of the next event of an instrument after `at`, `None` when it does not trade again
+        let next_event = |slot: &mut Slot, at: Duration| {
+            let next = at + Duration::from_secs_f64(interval.sample(&mut slot.rng));
+            sessions.map_or(Some(next), |s| s.next_event(slot.instrument.get_kind(), at, next))
+        };
+
+        let mut slots: Vec<Slot> = Vec::new();
+        let mut scheduler = Scheduler::new();
+        let mut listings = self.listings.load(Ordering::SeqCst);
+        let schedule = |slots: &mut Vec<Slot>, scheduler: &mut Scheduler, instrument: Arc<Instrument>, at: Duration| {
+            println!("Starting {}", instrument.get_name());
+            let rng = StdRng::seed_from_u64(seed ^ scheduler::stable_hash(instrument.get_name()));
+            let phase = phase(&instrument, at);
+            let mut slot = Slot { instrument, rng, previous: at, remaining: loops.saturating_sub(1), phase };
+            if slot.remaining == 0 {
+                println!("ending {}", slot.instrument.get_name());
+            } else if let Some(next) = next_event(&mut slot, at) {
+                scheduler.schedule(next, slots.len());
+            } else {
+                println!("ending {} closed", slot.instrument.get_name());
+            }
+            slots.push(slot);
+        };
+        for i in instruments {
+            schedule(&mut slots, &mut scheduler, i, Duration::ZERO);
+        }
+
+        let begin = clock.now();
+        while let Some((at, index)) = scheduler.next_event() {
+            clock.sleep_until(begin + at);
+            if listings != self.listings.load(Ordering::SeqCst) {
+                listings = self.listings.load(Ordering::SeqCst);
+                for i in self.sourced_instruments() {
+                    if !slots.iter().any(|s| Arc::ptr_eq(&s.instrument, &i)) {
+                        schedule(&mut slots, &mut scheduler, i, at);
                     }
-                    println!("ending {}", k);
-                });
+                }
             }
-        });
+            let slot = &mut slots[index];
+            let i = Arc::clone(&slot.instrument);
+            let phase = phase(&i, at);
+            let auction = Phase::auction_print(slot.phase, phase);
+            if slot.phase != phase {
+                println!("{} enters {:?}", i.get_name(), phase);
+                slot.phase = phase;
+            }
+            match i.status() {
+                Status::Delisted => {
+                    println!("ending {} delisted", i.get_name());
+                    continue;
+                }
+                Status::Halted => slot.remaining -= 1,
+                Status::Trading if phase == Phase::Continuous || auction.is_some() => {
+                    let dt = (at - slot.previous).as_secs_f64() / price_model::TRADING_DAY_SECS;
+                    slot.previous = at;
+                    let changes = i.tick(&mut slot.rng, dt, Condition::of(auction));
+                    if let Some(auction) = auction {
+                        i.auction_print(auction);
+                    }
+                    self.send_update(&i, &changes);
+                    slot.remaining -= 1;
+                }
+                // the price does not move while nothing trades
+                Status::Trading => slot.previous = at,
+            }
+            if slot.remaining == 0 {
+                println!("ending {}", i.get_name());
+            } else if let Some(next) = next_event(slot, at) {
+                scheduler.schedule(next, index);
+            } else {
+                println!("ending {} closed", i.get_name());
+            }
+        }
+        println!("finished");
+    }
+
+    // Method to drive the instruments from their historical bars instead of generating the prices:
+    // the bars of each instrument are read from `<dir>/<symbol>.csv` and played from the earliest one
+    // at the pace of `clock`, with `ticks` updates interpolated in each bar. The first update of a day
+    // prints its open and the last one its close. A halted instrument keeps following its bars
+    // without publishing, and a delisted one stops.
+    pub fn play_history(&self, dir: &str, ticks: usize, seed: u64, clock: &Clock) -> Result<(), String> {
+        println!("Playing feed {} from the bars in {}", self.name, dir);
+        let mut histories = Vec::new();
+        for i in self.sourced_instruments() {
+            let path = Path::new(dir).join(format!("{}.csv", i.get_name()));
+            if !path.exists() {
+                println!("no history for {}", i.get_name());
+                continue;
+            }
+            let mut ticks = history::ticks(history::load(&path)?, ticks).peekable();
+            if let Some(first) = ticks.peek().map(|tick| tick.time) {
+                let rng = StdRng::seed_from_u64(seed ^ scheduler::stable_hash(i.get_name()));
+                histories.push((i, rng, first, ticks));
+            }
+        }
+        let Some(start) = histories.iter().map(|(_, _, first, _)| *first).min() else {
+            println!("finished, no history in {}", dir);
+            return Ok(());
+        };
+
+        let mut scheduler = Scheduler::new();
+        let at = |tick: &Tick| (tick.time - start).to_std().unwrap();
+        for (index, (i, _, first, _)) in histories.iter().enumerate() {
+            println!("Starting {} at {}", i.get_name(), first);
+            scheduler.schedule((*first - start).to_std().unwrap(), index);
+        }
+        let begin = clock.now();
+        while let Some((time, index)) = scheduler.next_event() {
+            clock.sleep_until(begin + time);
+            let (i, rng, _, ticks) = &mut histories[index];
+            if i.status() == Status::Delisted {
+                println!("ending {} delisted", i.get_name());
+                continue;
+            }
+            let tick = ticks.next().unwrap();
+            let changes = i.trade_at(rng, tick.price, tick.size, Condition::of(tick.print));
+            if let Some(auction) = tick.print {
+                i.auction_print(auction);
+            }
+            self.send_update(i, &changes);
+            match ticks.peek() {
+                Some(next) => scheduler.schedule(at(next), index),
+                None => println!("ending {} at {}", i.get_name(), tick.time),
+            }
+        }
         println!("finished");
+        Ok(())
+    }
+
+    // Method to consume the prices streamed by the exchange at `host:port` instead of generating them.
+    // All the listed instruments are subscribed on one connection, read by the task calling it so that
+    // each connection has a task of its own, until each instrument received `loops` - 1 updates or the
+    // exchange closes the connection.
+    // Instruments listed or delisted meanwhile are subscribed or unsubscribed on the same connection.
+    pub fn consume(&self, host: &str, port: u16, loops: usize) -> Result<(), String> {
+        println!("Consuming feed {} from {}:{}", self.name, host, port);
+        let connection = client::Connection::connect(host, port).map_err(|e| format!("client Error::{}", e))?;
+        self.read_connection(connection, loops);
+        println!("finished");
+        Ok(())
+    }
+
+    // Method to replay a capture file instead of generating the prices: the recorded events are applied
+    // to the instruments and sent to the subscribers at the pace of `clock`, the recorded one when it is real,
+    // scaled by its speed, or as fast as possible when it is virtual. The synthetic instruments are priced
+    // from their constituents again rather than from their records.
+    pub fn replay(&self, path: &str, clock: &Clock) -> Result<(), String> {
+        println!("Replaying feed {} from {}", self.name, path);
+        let begin = clock.now();
+        let mut first = None;
+        let mut missing = BTreeSet::new();
+        for record in capture::read(path)? {
+            let Record { time, event } = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    println!("ERROR::{}", e);
+                    continue;
+                }
+            };
+            let first = *first.get_or_insert(time);
+            clock.sleep_until(begin + Duration::from_secs_f64((time - first).max(0.0)));
+            let symbol = match &event {
+                Event::Image { symbol, .. } | Event::Update { symbol, .. } | Event::Status { symbol, .. } => symbol,
+                _ => continue
+            };
+            let Some(i) = self.get(symbol) else {
+                if missing.insert(symbol.to_string()) {
+                    println!("ERROR::{} instrument not found", symbol);
+                }
+                continue;
+            };
+            if self.is_synthetic(symbol) {
+                continue;
+            }
+            match event {
+                Event::Image { quote, depth, .. } => {
+                    i.apply(&quote);
+                    i.set_depth(depth);
+                    self.send_image(&i);
+                }
+                Event::Update { quote, depth, .. } => {
+                    i.apply(&quote);
+                    i.apply_depth(&depth);
+                    self.send_update(&i, &depth);
+                }
+                Event::Status { symbol, status } => {
+                    let _ = match status {
+                        Status::Halted => self.halt(&symbol),
+                        Status::Trading => self.resume(&symbol),
+                        Status::Delisted => self.remove(&symbol).map(|_| ()),
+                    };
+                }
+                _ => {}
+            }
+        }
+        println!("finished");
+        Ok(())
+    }
+
+    // Subscribe the instruments listed since the last call and unsubscribe the delisted ones.
+    fn follow_listings(&self, connection: &mut client::Connection, remaining: &mut HashMap<String, usize>, loops: usize) -> io::Result<()> {
+        for i in self.sourced_instruments() {
+            if !remaining.contains_key(i.get_name()) {
+                connection.send(&Message::Subscribe { symbol: i.get_name().to_string() })?;
+                remaining.insert(i.get_name().to_string(), loops.saturating_sub(1));
+            }
+        }
+        for (symbol, n) in remaining.iter_mut().filter(|(_, n)| **n > 0) {
+            if self.get(symbol).is_none() {
+                println!("ending {} delisted", symbol);
+                connection.send(&Message::Unsubscribe { symbol: symbol.to_string() })?;
+                *n = 0;
+            }
+        }
+        Ok(())
+    }
+
+    // Apply the messages of an exchange connection to the instruments.
+    // A halted instrument keeps following the exchange, without publishing its updates.
+    fn read_connection(&self, mut connection: client::Connection, loops: usize) {
+        let mut remaining: HashMap<String, usize> = HashMap::new();
+        let mut listings = None;
+        loop {
+            let current = self.listings.load(Ordering::SeqCst);
+            if listings != Some(current) {
+                listings = Some(current);
+                if let Err(e) = self.follow_listings(&mut connection, &mut remaining, loops) {
+                    eprintln!("Error writing to exchange {}", e);
+                    break;
+                }
+            }
+            if !remaining.values().any(|&n| n > 0) {
+                break;
+            }
+            match connection.receive() {
+                Ok(Some(Message::Snapshot { symbol, quote, depth })) => {
+                    if let Some(i) = self.get(&symbol) {
+                        i.apply(&quote);
+                        i.set_depth(depth);
+                        self.send_image(&i);
+                    }
+                }
+                Ok(Some(Message::Update { symbol, quote, depth })) => {
+                    let (Some(i), Some(n)) = (self.get(&symbol), remaining.get_mut(&symbol)) else {
+                        continue;
+                    };
+                    if *n == 0 {
+                        continue;
+                    }
+                    i.apply(&quote);
+                    i.apply_depth(&depth);
+                    self.send_update(&i, &depth);
+                    *n -= 1;
+                    if *n == 0 {
+                        println!("ending {}", symbol);
+                        let _ = connection.send(&Message::Unsubscribe { symbol });
+                    }
+                }
+                Ok(Some(Message::Error { symbol, text })) => {
+                    eprintln!("ERROR from exchange::{}", text);
+                    if let Some(n) = symbol.and_then(|symbol| remaining.get_mut(&symbol)) {
+                        *n = 0;
+                    }
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    eprintln!("exchange closed the connection");
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("Error reading from exchange {}", e);
+                    break;
+                }
+            }
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYMBOLS: [&str; 3] = ["AAPL", "MSFT", "IBM"];
+
+    fn feed() -> DataFeed {
+        let feed = DataFeed::new("test".to_string());
+        for symbol in SYMBOLS {
+            feed.add(Instrument::new(Kind::Equity(symbol.to_string()))).unwrap();
+        }
+        feed
+    }
+
+    // A client of `feed` subscribed to every instrument, receiving the images at once.
+    fn subscribe(feed: &DataFeed) -> (Subscriber<'_>, mpsc::Receiver<Event>) {
+        let (sink, receiver) = Sink::channel();
+        let subscriber = feed.connect("test", sink);
+        for symbol in SYMBOLS {
+            subscriber.subscribe(symbol).unwrap();
+        }
+        (subscriber, receiver)
+    }
+
+    // Events received by a client subscribed to every instrument of a feed run with `seed`.
+    fn run(seed: u64) -> Vec<Event> {
+        let feed = feed();
+        let (_subscriber, receiver) = subscribe(&feed);
+        feed.start(50, seed, 100.0, &Clock::new(crate::clock::Mode::Virtual), None);
+        receiver.try_iter().collect()
+    }
+
+    #[test]
+    fn same_seed_same_events() {
+        let events = run(7);
+        // the image of each instrument then its 49 updates
+        assert_eq!(events.len(), 3 * 50);
+        assert_eq!(events, run(7));
+        assert_ne!(events, run(8));
+    }
+
+    #[test]
+    fn replay_sends_the_recorded_events() {
+        let path = std::env::temp_dir().join(format!("replay-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        let live = feed();
+        let (_subscriber, receiver) = subscribe(&live);
+        let clock = Arc::new(Clock::new(crate::clock::Mode::Virtual));
+        live.record(path, Arc::clone(&clock)).unwrap();
+        live.start(20, 7, 100.0, &clock, None);
+        live.stop_recording();
+        let sent: Vec<Event> = receiver.try_iter().collect();
+
+        let records: Vec<Record> = capture::read(path).unwrap().map(Result::unwrap).collect();
+        let (times, recorded): (Vec<f64>, Vec<Event>) = records.into_iter().map(|r| (r.time, r.event)).unzip();
+        assert!(times.windows(2).all(|t| t[0] <= t[1]));
+        assert_eq!(recorded[3..], sent[3..]);
+        let replayed = feed();
+        let (_subscriber, receiver) = subscribe(&replayed);
+        let replay_clock = Clock::new(crate::clock::Mode::Virtual);
+        replayed.replay(path, &replay_clock).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let received: Vec<Event> = receiver.try_iter().collect();
+        // the images of the subscription, the recorded images then the updates, at their recorded time
+        assert_eq!(received.len(), sent.len() + SYMBOLS.len());
+        assert_eq!(received[..3], sent[..3]);
+        assert_eq!(received[3..6], recorded[..3]);
+        assert_eq!(received[6..], sent[3..]);
+        assert_eq!(replay_clock.now().as_secs_f64(), times[times.len() - 1] - times[0]);
+    }
+
+    #[test]
+    fn depth_updates_rebuild_the_image() {
+        let i = Instrument::new(Kind::Equity("AAPL".to_string()));
+        let mut rng = StdRng::seed_from_u64(7);
+        i.tick(&mut rng, 1e-3, Condition::Regular);
+        let Event::Image { quote, mut depth, .. } = i.on_image() else { panic!("image expected") };
+        assert_eq!((depth.bids.len(), depth.asks.len()), (DEPTH_LEVELS as usize, DEPTH_LEVELS as usize));
+        assert!(depth.bids.windows(2).all(|l| l[0].price > l[1].price));
+        assert!(depth.asks.windows(2).all(|l| l[0].price < l[1].price));
+        assert_eq!((quote.bid, quote.ask), (depth.bids[0].price, depth.asks[0].price));
+        assert!(quote.bid < quote.ask);
+        // the changes of the next step turn the depth of the image into the new one
+        let changes = i.tick(&mut rng, 1e-3, Condition::Regular);
+        let Event::Update { depth: update, .. } = i.on_update(&changes) else { panic!("update expected") };
+        assert!(!update.is_empty());
+        for change in &update {
+            depth.apply(change);
+        }
+        assert_eq!(depth, *i.data.depth.read().unwrap());
+    }
+
+    // Symbols of the images and updates received so far.
+    fn symbols(receiver: &mpsc::Receiver<Event>) -> Vec<String> {
+        receiver.try_iter().map(|event| match event {
+            Event::Image { symbol, .. } | Event::Update { symbol, .. } => symbol,
+            other => panic!("image or update expected, got {:?}", other)
+        }).collect()
+    }
+
+    #[test]
+    fn updates_go_to_the_subscribers_only() {
+        let feed = feed();
+        let (sink, first) = Sink::channel();
+        let a = feed.connect("a", sink);
+        let (sink, second) = Sink::channel();
+        let b = feed.connect("b", sink);
+        a.subscribe("AAPL").unwrap();
+        a.subscribe("MSFT").unwrap();
+        b.subscribe("MSFT").unwrap();
+        assert_eq!(symbols(&first), ["AAPL", "MSFT"]);
+        assert_eq!(symbols(&second), ["MSFT"]);
+
+        a.unsubscribe("MSFT").unwrap();
+        assert!(a.unsubscribe("MSFT").is_err());
+        for symbol in SYMBOLS {
+            feed.send_update(&feed.get(symbol).unwrap(), &[]);
+        }
+        assert_eq!(symbols(&first), ["AAPL"]);
+        assert_eq!(symbols(&second), ["MSFT"]);
+
+        // dropping a subscriber disconnects it
+        let id = b.id;
+        drop(b);
+        assert!(feed.get("MSFT").unwrap().get_subscriber_ids().is_empty());
+        assert!(feed.subscribe(id, "MSFT").is_err());
+    }
+
+    #[test]
+    fn events_reach_callbacks_and_broadcast_receivers() {
+        let feed = feed();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = {
+            let seen = Arc::clone(&seen);
+            Sink::callback(move |event| seen.lock().unwrap().push(event.clone()))
+        };
+        let callback = feed.connect("callback", sink);
+        let (sink, mut receiver) = Sink::broadcast(16);
+        let broadcast = feed.connect("broadcast", sink);
+        callback.subscribe("AAPL").unwrap();
+        broadcast.subscribe("AAPL").unwrap();
+        let aapl = feed.get("AAPL").unwrap();
+        feed.send_update(&aapl, &[]);
+
+        let expected = vec![aapl.on_image(), aapl.on_update(&[])];
+        assert_eq!(*seen.lock().unwrap(), expected);
+        let received: Vec<Event> = std::iter::from_fn(|| receiver.try_recv().ok()).collect();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn chain_subscribers_follow_the_membership() {
+        let feed = feed();
+        feed.add_chain("0#TECH", vec!["AAPL".to_string(), "MSFT".to_string()]);
+        let (sink, receiver) = Sink::channel();
+        let subscriber = feed.connect("test", sink);
+        assert_eq!(subscriber.subscribe_chain("0#TECH").unwrap(), ["AAPL", "MSFT"]);
+        assert!(subscriber.subscribe_chain("0#BANKS").is_err());
+
+        feed.add_constituent("0#TECH", "IBM").unwrap();
+        assert!(feed.add_constituent("0#TECH", "IBM").is_err());
+        // a delisted instrument leaves its chains
+        feed.remove("MSFT").unwrap();
+        subscriber.unsubscribe_chain("0#TECH").unwrap();
+        feed.remove_constituent("0#TECH", "AAPL").unwrap();
+
+        let chain = |added: &[&str], removed: &[&str]| Event::ChainUpdate {
+            chain: "0#TECH".to_string(),
+            added: added.iter().map(|s| s.to_string()).collect(),
+            removed: removed.iter().map(|s| s.to_string()).collect(),
+        };
+        let image = Event::ChainImage { chain: "0#TECH".to_string(), constituents: vec!["AAPL".to_string(), "MSFT".to_string()] };
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![image, chain(&["IBM"], &[]), chain(&[], &["MSFT"])]);
+        assert_eq!(feed.get_chain("0#TECH"), Some(vec!["IBM".to_string()]));
+    }
+
+    #[test]
+    fn instruments_are_halted_delisted_and_listed_again() {
+        let feed = feed();
+        let (subscriber, receiver) = subscribe(&feed);
+        receiver.try_iter().for_each(drop);
+        let status = |symbol: &str, status| Event::Status { symbol: symbol.to_string(), status };
+
+        let aapl = feed.get("AAPL").unwrap();
+        feed.halt("AAPL").unwrap();
+        assert!(feed.halt("AAPL").is_err());
+        // nothing is published while halted, a fresh image when trading resumes
+        feed.send_update(&aapl, &[]);
+        feed.resume("AAPL").unwrap();
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![status("AAPL", Status::Halted), status("AAPL", Status::Trading), aapl.on_image()]);
+
+        // a delisted instrument outlives the feed listing it
+        let ibm = feed.remove("IBM").unwrap();
+        assert_eq!(ibm.status(), Status::Delisted);
+        assert!(feed.get("IBM").is_none());
+        let listed = feed.add(Instrument::new(Kind::Equity("IBM".to_string()))).unwrap();
+        assert!(listed.get_subscriber_ids().is_empty());
+        subscriber.subscribe("IBM").unwrap();
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![status("IBM", Status::Delisted), listed.on_image()]);
+    }
+}
@@ -0,0 +1,16 @@
+// Market data feed simulator as a library: the instruments and the data feeds delivering
+// their events to subscribers, the exchange simulator they can consume and its protocols.
+#[path = "instrument.rs"] pub mod instrument;
+#[path = "exchange_simulator.rs"] pub mod exchange_simulator;
+#[path = "price_model.rs"] pub mod price_model;
+#[path = "scheduler.rs"] pub mod scheduler;
+#[path = "clock.rs"] pub mod clock;
+#[path = "protocol.rs"] pub mod protocol;
+#[path = "dictionary.rs"] pub mod dictionary;
+#[path = "pattern.rs"] pub mod pattern;
+#[path = "session.rs"] pub mod session;
+#[path = "capture.rs"] pub mod capture;
+#[path = "history.rs"] pub mod history;
+#[path = "bars.rs"] pub mod bars;
+#[path = "indicators.rs"] pub mod indicators;
+#[path = "synthetic.rs"] pub mod synthetic;
@@ -1,13 +1,49 @@
 use std::thread;
-use std::path::PathBuf;
+use std::sync::Arc;
 use std::str::FromStr;
+use rand::Rng;
 use structopt::StructOpt;
-use std::error::Error;
-#[path = "instrument.rs"] mod instrument;
+use cli::{clock, dictionary, exchange_simulator, instrument, session};
+use cli::bars::BarSpec;
+use cli::indicators;
+use cli::instrument::{Event, Sink};
+use cli::pattern::Pattern;
 #[path = "alphavantageapi.rs"] mod alphavantageapi;
-#[path = "exchange_simulator.rs"] mod exchange_simulator;
 
 
+// Where the prices of the instruments come from.
+#[derive(Debug)]
+enum Source {
+    Local,              // generated by the feed itself
+    Exchange(String),   // streamed by the exchange simulator at host:port
+    Replay(String),     // read from a capture file
+    History(String)     // interpolated from the historical bars of a directory
+}
+
+impl FromStr for Source {
+    type Err = String;
+    fn from_str(source:&str) -> Result<Self, Self::Err> {
+        match source {
+            "local" => Ok(Source::Local),
+            "exchange" => Ok(Source::Exchange("127.0.0.1:7878".to_string())),
+            _ => match (source.strip_prefix("exchange:"), source.strip_prefix("replay:"), source.strip_prefix("bars:")) {
+                (Some(address), _, _) if address.contains(':') => Ok(Source::Exchange(address.to_string())),
+                (_, Some(path), _) if !path.is_empty() => Ok(Source::Replay(path.to_string())),
+                (_, _, Some(dir)) if !dir.is_empty() => Ok(Source::History(dir.to_string())),
+                _ => Err(std::format!("source should be 'local', 'exchange', 'exchange:host:port', 'replay:file' or 'bars:directory', but is '{}'", source))
+            }
+        }
+    }
+}
+
+// Parse the average number of updates per second, a finite positive number.
+fn parse_rate(rate: &str) -> Result<f64, String> {
+    match rate.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        _ => Err(std::format!("rate should be a positive number of updates per second, but is '{}'", rate))
+    }
+}
+
 #[derive(Debug)]
 enum OutType {
     File,
@@ -31,37 +67,88 @@ impl FromStr for OutType {
 #[structopt(version = "0.1.2")]
 #[structopt(about = "Pippo evaluates rust")]
 struct Opt {
+    #[allow(dead_code)]
     #[structopt(short, long)]
     debug: bool,
 
     /// Verbose mode (-v, -vv, -vvv, etc.)
+    #[allow(dead_code)]
     #[structopt(short, long, parse(from_occurrences))]
     verbose: u8,
 
     /// Set rate avg update/sec
-    #[structopt(short, long, default_value = "42")]
+    #[structopt(short, long, default_value = "42", parse(try_from_str = parse_rate))]
     rate: f64,
 
     /// Number of loops
     #[structopt(short, long, default_value = "15")]
     loops: usize,
 
+    /// Simulation clock : real, virtual (as fast as possible) or a speed like 100x
+    #[structopt(short, long, default_value = "real")]
+    clock: clock::Mode,
+
+    /// Seed of the random generators, the same seed replays the same updates
+    #[structopt(long)]
+    seed: Option<u64>,
+
+    /// Date and time the simulation starts at, like 2024-03-04T08:45, to follow the trading sessions;
+    /// trading is continuous without it
+    #[structopt(long, parse(try_from_str = session::parse_start))]
+    start: Option<chrono::NaiveDateTime>,
+
+    /// Dictionary of the instruments (JSON file)
+    #[structopt(long, default_value = "./data.json")]
+    dictionary: String,
 
-    /// list of instruments to subscrie
+    /// Capture file recording the images and updates of the feed (JSON lines)
+    #[structopt(long)]
+    record: Option<String>,
+
+    /// End of day snapshot of the instruments, written as a dictionary the next run can start from
+    #[structopt(long)]
+    snapshot: Option<String>,
+
+
+    /// list of instruments to subscrie, or patterns like *=, EUR*, kind:Equity or re:^[A-Z]+=$,
+    /// or the bars of an instrument like AAPL@1m, AAPL@100t or AAPL@5000v
     #[structopt(short, long)]
     subscribe: Vec<String>,
 
     /// type of output : stdout or file
+    #[allow(dead_code)]
     #[structopt(short="t", long)]
     out_type: Option<OutType>,
 
+    /// Bars written for every instrument, like 1s, 1m, 5m, 1h, 100t or 5000v
+    #[structopt(long)]
+    bars: Vec<BarSpec>,
+
+    /// Indicators computed on every instrument, like sma:20, ema:20, rsi:14, macd:12:26:9, bb:20:2 or atr:14:10
+    #[structopt(long)]
+    indicator: Vec<indicators::Spec>,
+
+    /// Directory of the bar files, one CSV file per instrument and bars
+    #[structopt(long, default_value = "./bars")]
+    bars_dir: String,
+
     /// using a api or not
     #[structopt(short, long)]
     use_api: bool,
 
     ///datafeed
     #[structopt(short, long)]
-    feed: String
+    feed: String,
+
+    /// source of the prices : local, exchange, exchange:host:port, replay:file or bars:directory of SYMBOL.csv
+    /// OHLCV files, replays and bars being paced by the clock. The exchange starts streaming on the first
+    /// subscription it receives, so unlike the other sources it does not reproduce a run from a seed
+    #[structopt(long, default_value = "local")]
+    source: Source,
+
+    /// Number of updates interpolated in each historical bar, at least 4
+    #[structopt(long, default_value = "4")]
+    ticks_per_bar: usize
 }
 
 #[tokio::main]
@@ -84,27 +171,58 @@ fn register(datafeed:&instrument::DataFeed, instrument:&instrument::Instrument)
 */
 
 async fn do_it(opt : &Opt) {  
-    let mut reuters = instrument::DataFeed::new(opt.feed.to_string());
+    let clock = Arc::new(clock::Clock::new(opt.clock));
+    let reuters = instrument::DataFeed::new(opt.feed.to_string()).with_clock(Arc::clone(&clock));
+    let seed = opt.seed.unwrap_or_else(|| rand::thread_rng().gen());
 
-    thread::spawn(|| {
-        exchange_simulator::start_exchange();
+    let dictionary = match dictionary::load(&opt.dictionary) {
+        Ok(dictionary) => dictionary,
+        Err(e) => {
+            println!("ERROR::{}", e);
+            return;
+        }
+    };
+    let instruments = dictionary.instruments();
+    let synthetics = dictionary.synthetics();
+    let mut end_of_day = opt.snapshot.as_ref().map(|_| dictionary.clone());
+    let sessions = opt.start.map(|start| Arc::new(session::Sessions::new(start, &dictionary.sessions)));
+    for (name, constituents) in &dictionary.chains {
+        reuters.add_chain(name, constituents.clone());
+    }
+
+    let exchange_clock = Arc::clone(&clock);
+    let rate = opt.rate;
+    let exchange_sessions = sessions.clone();
+    thread::spawn(move || {
+        if let Err(e) = exchange_simulator::start_exchange(exchange_clock, rate, seed, dictionary, exchange_sessions) {
+            println!("ERROR::{}", e);
+        }
     });
 
-    let dictionary = vec![
-        instrument::Instrument::new(instrument::Kind::Equity("AAPL".to_string())),
-        instrument::Instrument::new(instrument::Kind::Equity("MSFT".to_string())),
-        instrument::Instrument::new(instrument::Kind::Currency("EUR=".to_string())),
-        instrument::Instrument::new(instrument::Kind::Currency("CHF=".to_string())),
-        instrument::Instrument::new(instrument::Kind::Currency("IDR=".to_string())),
-        instrument::Instrument::new(instrument::Kind::Bond("46590XAR7=".to_string()))
-    ];
-
-    for i in dictionary.iter() {
-        reuters.add(&i).await;
+    for i in instruments {
+        let attached = opt.indicator.iter().try_for_each(|spec| i.attach(*spec));
+        if let Err(e) = attached.and_then(|_| reuters.add(i).map(|_| ())) {
+            println!("ERROR::{}", e);
+        }
+    }
+    for (i, expression) in synthetics {
+        let attached = opt.indicator.iter().try_for_each(|spec| i.attach(*spec));
+        match attached.and_then(|_| reuters.add_synthetic(i, expression.clone())) {
+            Ok(i) => println!("synthetic {} = {}", i.get_name(), expression),
+            Err(e) => println!("ERROR::{}", e),
+        }
     }
 
     let api_key = "votre_clé_api"; // Remplacez par votre clé API Marketstack.
     let api = alphavantageapi::AlphaVantageApi::new(api_key.to_string());
+    let console = reuters.connect("console", Sink::callback(|event| match event {
+        Event::Image { symbol, .. } => println!("Image for {} {:?}", symbol, event),
+        Event::Update { symbol, .. } => eprintln!("Update for {} {:?}", symbol, event),
+        Event::ChainImage { chain, .. } => println!("Image for {} {:?}", chain, event),
+        Event::ChainUpdate { chain, .. } => eprintln!("Update for {} {:?}", chain, event),
+        Event::Status { symbol, .. } => println!("Status for {} {:?}", symbol, event),
+        Event::Bar { symbol, .. } => println!("Bar for {} {:?}", symbol, event),
+    }));
     for ric in opt.subscribe.iter() {
 
         if opt.use_api {
@@ -118,9 +236,33 @@ async fn do_it(opt : &Opt) {
                 }
             }
         }
-        match reuters.subscribe(ric.to_string()) {
-            Ok(&ref _i) => {
-                println!("subscribed {:?}", ric);
+        if let Some((symbol, spec)) = ric.split_once('@') {
+            match spec.parse::<BarSpec>().and_then(|spec| console.subscribe_bars(symbol, spec)) {
+                Ok(()) => println!("subscribed the {} bars of {:?}", spec, symbol),
+                Err(e) => println!("ERROR::{}", e),
+            }
+            continue;
+        }
+        match ric.parse::<Pattern>() {
+            Ok(Pattern::Symbol(_)) if reuters.get_chain(ric).is_some() => match console.subscribe_chain(ric) {
+                Ok(constituents) => {
+                    println!("subscribed chain {:?} of {:?}", ric, constituents);
+                }
+                Err(e) => {
+                    println!("ERROR::{}", e);
+                }
+            },
+            Ok(Pattern::Symbol(_)) => match console.subscribe(ric) {
+                Ok(_) => {
+                    println!("subscribed {:?}", ric);
+                }
+                Err(e) => {
+                    println!("ERROR::{}", e);
+                }
+            },
+            Ok(pattern) => {
+                let matching: Vec<String> = console.subscribe_pattern(pattern).iter().map(|i| i.get_name().to_string()).collect();
+                println!("subscribed {:?} matching {:?}", ric, matching);
             }
             Err(e) => {
                 println!("ERROR::{}", e);
@@ -129,10 +271,53 @@ async fn do_it(opt : &Opt) {
     }
 
 
-    reuters.start(opt.loops);
+    if let Some(path) = &opt.record {
+        if let Err(e) = reuters.record(path, Arc::clone(&clock)) {
+            println!("ERROR::{}", e);
+        }
+    }
+    for spec in &opt.bars {
+        if let Err(e) = reuters.write_bars(&opt.bars_dir, *spec) {
+            println!("ERROR::{}", e);
+        }
+    }
+
+    match &opt.source {
+        Source::Local => reuters.start(opt.loops, seed, opt.rate, &clock, sessions.as_deref()),
+        Source::Exchange(address) => {
+            let (host, port) = address.rsplit_once(':').unwrap();
+            let result = port.parse::<u16>()
+                .map_err(|e| format!("bad port {}::{}", port, e))
+                .and_then(|port| reuters.consume(host, port, opt.loops));
+            if let Err(e) = result {
+                println!("ERROR::{}", e);
+            }
+        }
+        Source::Replay(path) => {
+            if let Err(e) = reuters.replay(path, &clock) {
+                println!("ERROR::{}", e);
+            }
+        }
+        Source::History(dir) => {
+            if let Err(e) = reuters.play_history(dir, opt.ticks_per_bar, seed, &clock) {
+                println!("ERROR::{}", e);
+            }
+        }
+    }
+    reuters.stop_recording();
+    reuters.finish_bars();
+
+    if let (Some(path), Some(dictionary)) = (&opt.snapshot, &mut end_of_day) {
+        dictionary.end_of_day(&reuters.instruments());
+        match dictionary::save(dictionary, path) {
+            Ok(()) => println!("end of day snapshot written to {}", path),
+            Err(e) => println!("ERROR::{}", e),
+        }
+    }
 }
 
 
+#[allow(dead_code)]
 fn dummy<T: std::fmt::Debug>(d:T) {
     println!("dummy::{:?}", d);
 }
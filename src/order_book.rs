@@ -0,0 +1,649 @@
+// Central limit order book of one symbol, matching orders with price-time priority.
+// During an auction the orders only accumulate, the book may cross, until it is uncrossed
+// at the single price executing the most volume.
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{self, BookSide, Depth, DepthChange};
+
+pub type OrderId = u64;
+// Session owning an order.
+pub type Owner = usize;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Buy,
+    Sell
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    New,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    Replaced
+}
+
+// How the book treats the incoming orders.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BookState {
+    Continuous, // orders match as they arrive
+    Auction,    // limit orders accumulate without matching, market orders are refused
+    Closed      // only cancellations are accepted
+}
+
+// An order resting in the book, `price` in ticks.
+#[derive(Debug)]
+struct Order {
+    id: OrderId,
+    owner: Owner,
+    side: Side,
+    price: i64,
+    quantity: u64, // total quantity of the order
+    filled: u64,
+}
+
+impl Order {
+    fn leaves(&self) -> u64 {
+        self.quantity - self.filled
+    }
+}
+
+// A trade between the incoming order and a resting one (the maker).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fill {
+    pub maker: OrderId,
+    pub maker_owner: Owner,
+    pub maker_filled: u64,
+    pub maker_leaves: u64,
+    pub price: f64,
+    pub quantity: u64,
+}
+
+// New state of a price level after an order, a quantity of 0 means the level is gone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Level {
+    pub side: Side,
+    pub price: f64,
+    pub quantity: u64,
+    pub orders: usize,
+}
+
+impl From<Level> for DepthChange {
+    fn from(level: Level) -> DepthChange {
+        DepthChange {
+            side: if level.side == Side::Buy { BookSide::Bid } else { BookSide::Ask },
+            price: level.price,
+            size: level.quantity,
+            orders: level.orders,
+        }
+    }
+}
+
+// Outcome of the uncrossing of an auction: its price, the fills of the orders on
+// both sides, each reported as a maker, and the levels of the book it changed.
+#[derive(Debug, Clone)]
+pub struct Uncross {
+    pub price: f64,
+    pub fills: Vec<Fill>,
+    pub book: Vec<Level>,
+}
+
+// Outcome of an order: its state, the trades it made and the levels of the book it changed.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub order: OrderId,
+    pub status: Status,
+    pub filled: u64,
+    pub leaves: u64,
+    pub fills: Vec<Fill>,
+    pub book: Vec<Level>,
+}
+
+pub struct OrderBook {
+    ticks_per_unit: f64, // inverse of the tick size, dividing by it keeps prices readable
+    bids: BTreeMap<i64, VecDeque<Order>>,
+    asks: BTreeMap<i64, VecDeque<Order>>,
+    orders: HashMap<OrderId, (Side, i64)>, // where each resting order is
+    next_id: OrderId,
+    last: Option<f64>, // price of the last trade
+    state: BookState,
+}
+
+impl OrderBook {
+    pub fn new(tick_size: f64) -> OrderBook {
+        OrderBook {
+            ticks_per_unit: (1.0 / tick_size).round(),
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            orders: HashMap::new(),
+            next_id: 1,
+            last: None,
+            state: BookState::Continuous,
+        }
+    }
+
+    pub fn state(&self) -> BookState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: BookState) {
+        self.state = state;
+    }
+
+    fn accepting(&self) -> Result<(), String> {
+        if self.state == BookState::Closed {
+            Err("the book is closed".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn to_ticks(&self, price: f64) -> i64 {
+        (price * self.ticks_per_unit).round() as i64
+    }
+
+    fn to_price(&self, ticks: i64) -> f64 {
+        ticks as f64 / self.ticks_per_unit
+    }
+
+    // Round a price to the tick size of the book.
+    pub fn round(&self, price: f64) -> f64 {
+        self.to_price(self.to_ticks(price))
+    }
+
+    fn levels(&mut self, side: Side) -> &mut BTreeMap<i64, VecDeque<Order>> {
+        match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        }
+    }
+
+    fn level(&self, side: Side, price: i64) -> Level {
+        let orders = match side {
+            Side::Buy => self.bids.get(&price),
+            Side::Sell => self.asks.get(&price),
+        };
+        Level {
+            side,
+            price: self.to_price(price),
+            quantity: orders.map(|o| o.iter().map(Order::leaves).sum()).unwrap_or(0),
+            orders: orders.map(|o| o.len()).unwrap_or(0),
+        }
+    }
+
+    fn book_updates(&self, touched: BTreeSet<(Side, i64)>) -> Vec<Level> {
+        touched.into_iter().map(|(side, price)| self.level(side, price)).collect()
+    }
+
+    // Best bid as (price, quantity).
+    pub fn best_bid(&self) -> Option<(f64, u64)> {
+        self.bids.keys().next_back().map(|&p| (self.to_price(p), self.level(Side::Buy, p).quantity))
+    }
+
+    // Best ask as (price, quantity).
+    pub fn best_ask(&self) -> Option<(f64, u64)> {
+        self.asks.keys().next().map(|&p| (self.to_price(p), self.level(Side::Sell, p).quantity))
+    }
+
+    // Every level of the book, best price first.
+    pub fn depth(&self) -> Depth {
+        let level = |side, price: &i64| {
+            let Level { price, quantity, orders, .. } = self.level(side, *price);
+            protocol::Level { price, size: quantity, orders }
+        };
+        Depth {
+            bids: self.bids.keys().rev().map(|p| level(Side::Buy, p)).collect(),
+            asks: self.asks.keys().map(|p| level(Side::Sell, p)).collect(),
+        }
+    }
+
+    // Price of the last trade.
+    pub fn last(&self) -> Option<f64> {
+        self.last
+    }
+
+    // Match an incoming order against the opposite side while its limit allows it,
+    // nothing matches during an auction.
+    fn execute(&mut self, side: Side, limit: Option<i64>, quantity: u64, touched: &mut BTreeSet<(Side, i64)>) -> Vec<Fill> {
+        let mut fills = Vec::new();
+        if self.state == BookState::Auction {
+            return fills;
+        }
+        let mut remaining = quantity;
+        while remaining > 0 {
+            let best = match side {
+                Side::Buy => self.asks.keys().next().copied(),
+                Side::Sell => self.bids.keys().next_back().copied(),
+            };
+            let price = match best {
+                Some(price) if limit.is_none_or(|l| if side == Side::Buy { price <= l } else { price >= l }) => price,
+                _ => break
+            };
+            let maker_side = if side == Side::Buy { Side::Sell } else { Side::Buy };
+            let trade_price = self.to_price(price);
+            let queue = self.levels(maker_side).get_mut(&price).unwrap();
+            let maker = queue.front_mut().unwrap();
+            let traded = remaining.min(maker.leaves());
+            maker.filled += traded;
+            remaining -= traded;
+            fills.push(Fill {
+                maker: maker.id,
+                maker_owner: maker.owner,
+                maker_filled: maker.filled,
+                maker_leaves: maker.leaves(),
+                price: trade_price,
+                quantity: traded,
+            });
+            if maker.leaves() == 0 {
+                let id = maker.id;
+                queue.pop_front();
+                if queue.is_empty() {
+                    self.levels(maker_side).remove(&price);
+                }
+                self.orders.remove(&id);
+            }
+            touched.insert((maker_side, price));
+            self.last = Some(trade_price);
+        }
+        fills
+    }
+
+    // Match then rest the remaining quantity of a limit order.
+    fn place(&mut self, mut order: Order, touched: &mut BTreeSet<(Side, i64)>) -> Vec<Fill> {
+        let fills = self.execute(order.side, Some(order.price), order.leaves(), touched);
+        order.filled += fills.iter().map(|f| f.quantity).sum::<u64>();
+        if order.leaves() > 0 {
+            self.orders.insert(order.id, (order.side, order.price));
+            touched.insert((order.side, order.price));
+            self.levels(order.side).entry(order.price).or_default().push_back(order);
+        }
+        fills
+    }
+
+    fn status(filled: u64, leaves: u64) -> Status {
+        if leaves == 0 {
+            Status::Filled
+        } else if filled > 0 {
+            Status::PartiallyFilled
+        } else {
+            Status::New
+        }
+    }
+
+    // Enter a limit order, what cannot trade immediately rests in the book.
+    pub fn limit(&mut self, owner: Owner, side: Side, price: f64, quantity: u64) -> Result<Report, String> {
+        self.accepting()?;
+        if quantity == 0 {
+            return Err("quantity should be positive".to_string());
+        }
+        // a price rounding to no tick would rest below any bid
+        if !price.is_finite() || self.to_ticks(price) <= 0 {
+            return Err(format!("price should be at least one tick, but is {}", price));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut touched = BTreeSet::new();
+        let price = self.to_ticks(price);
+        let fills = self.place(Order { id, owner, side, price, quantity, filled: 0 }, &mut touched);
+        let filled = fills.iter().map(|f| f.quantity).sum();
+        Ok(Report {
+            order: id,
+            status: OrderBook::status(filled, quantity - filled),
+            filled,
+            leaves: quantity - filled,
+            fills,
+            book: self.book_updates(touched),
+        })
+    }
+
+    // Enter a market order, what cannot trade immediately is cancelled.
+    pub fn market(&mut self, side: Side, quantity: u64) -> Result<Report, String> {
+        self.accepting()?;
+        if self.state == BookState::Auction {
+            return Err("market orders are not accepted during an auction".to_string());
+        }
+        if quantity == 0 {
+            return Err("quantity should be positive".to_string());
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut touched = BTreeSet::new();
+        let fills = self.execute(side, None, quantity, &mut touched);
+        let filled = fills.iter().map(|f| f.quantity).sum();
+        Ok(Report {
+            order: id,
+            status: if filled == quantity { Status::Filled } else { Status::Cancelled },
+            filled,
+            leaves: 0,
+            fills,
+            book: self.book_updates(touched),
+        })
+    }
+
+    // Remove a resting order from the book.
+    fn remove(&mut self, id: OrderId) -> Result<Order, String> {
+        let (side, price) = self.orders.remove(&id).ok_or(format!("order {} not found", id))?;
+        let queue = self.levels(side).get_mut(&price).unwrap();
+        let position = queue.iter().position(|o| o.id == id).unwrap();
+        let order = queue.remove(position).unwrap();
+        if queue.is_empty() {
+            self.levels(side).remove(&price);
+        }
+        Ok(order)
+    }
+
+    // Cancel a resting order.
+    pub fn cancel(&mut self, id: OrderId) -> Result<Report, String> {
+        let order = self.remove(id)?;
+        let mut touched = BTreeSet::new();
+        touched.insert((order.side, order.price));
+        Ok(Report {
+            order: id,
+            status: Status::Cancelled,
+            filled: order.filled,
+            leaves: 0,
+            fills: Vec::new(),
+            book: self.book_updates(touched),
+        })
+    }
+
+    // Change the price and the total quantity of a resting order.
+    // The order keeps its time priority when only its quantity decreases.
+    pub fn replace(&mut self, id: OrderId, price: f64, quantity: u64) -> Result<Report, String> {
+        self.accepting()?;
+        let &(side, old_price) = self.orders.get(&id).ok_or(format!("order {} not found", id))?;
+        if !price.is_finite() || self.to_ticks(price) <= 0 {
+            return Err(format!("price should be at least one tick, but is {}", price));
+        }
+        let price = self.to_ticks(price);
+        let mut touched = BTreeSet::new();
+        touched.insert((side, old_price));
+        let queue = self.levels(side).get_mut(&old_price).unwrap();
+        let order = queue.iter_mut().find(|o| o.id == id).unwrap();
+        if quantity <= order.filled {
+            return Err(format!("quantity {} is not above the filled quantity {}", quantity, order.filled));
+        }
+        let (fills, filled) = if price == old_price && quantity <= order.quantity {
+            order.quantity = quantity;
+            (Vec::new(), order.filled)
+        } else {
+            let order = self.remove(id)?;
+            let filled = order.filled;
+            let fills = self.place(Order { price, quantity, ..order }, &mut touched);
+            let filled = filled + fills.iter().map(|f| f.quantity).sum::<u64>();
+            (fills, filled)
+        };
+        Ok(Report {
+            order: id,
+            status: if filled == quantity { Status::Filled } else { Status::Replaced },
+            filled,
+            leaves: quantity - filled,
+            fills,
+            book: self.book_updates(touched),
+        })
+    }
+
+    // Quantity of the bids at `price` or above, and of the asks at `price` or below.
+    fn executable(&self, price: i64) -> (u64, u64) {
+        let leaves = |orders: &VecDeque<Order>| orders.iter().map(Order::leaves).sum::<u64>();
+        let demand = self.bids.range(price..).map(|(_, o)| leaves(o)).sum();
+        let supply = self.asks.range(..=price).map(|(_, o)| leaves(o)).sum();
+        (demand, supply)
+    }
+
+    // Equilibrium of the auction in ticks with its volume: the limit price executing the most volume,
+    // then leaving the least surplus, then the nearest to the last trade, then the lowest.
+    fn equilibrium(&self) -> Option<(i64, u64)> {
+        let reference = self.last.map(|last| self.to_ticks(last));
+        let prices: BTreeSet<i64> = self.bids.keys().chain(self.asks.keys()).copied().collect();
+        prices.into_iter()
+            .map(|price| {
+                let (demand, supply) = self.executable(price);
+                let distance = reference.map_or(0, |r| (price - r).abs());
+                (price, demand.min(supply), demand.abs_diff(supply), distance)
+            })
+            .filter(|&(_, volume, _, _)| volume > 0)
+            .min_by_key(|&(price, volume, surplus, distance)| (std::cmp::Reverse(volume), surplus, distance, price))
+            .map(|(price, volume, _, _)| (price, volume))
+    }
+
+    // Indicative price and volume of the auction, `None` while the book does not cross.
+    pub fn indicative(&self) -> Option<(f64, u64)> {
+        self.equilibrium().map(|(price, volume)| (self.to_price(price), volume))
+    }
+
+    // Execute the crossing orders at the equilibrium price, in price then time priority.
+    // The book keeps its state, `None` when it does not cross.
+    pub fn uncross(&mut self) -> Option<Uncross> {
+        let (price, volume) = self.equilibrium()?;
+        let trade_price = self.to_price(price);
+        let mut touched = BTreeSet::new();
+        let mut fills = Vec::new();
+        let mut remaining = volume;
+        while remaining > 0 {
+            let bid = *self.bids.keys().next_back().unwrap();
+            let ask = *self.asks.keys().next().unwrap();
+            let traded = {
+                let buyer = self.bids[&bid].front().unwrap().leaves();
+                let seller = self.asks[&ask].front().unwrap().leaves();
+                remaining.min(buyer).min(seller)
+            };
+            for (side, level) in [(Side::Buy, bid), (Side::Sell, ask)] {
+                let queue = self.levels(side).get_mut(&level).unwrap();
+                let order = queue.front_mut().unwrap();
+                order.filled += traded;
+                fills.push(Fill {
+                    maker: order.id,
+                    maker_owner: order.owner,
+                    maker_filled: order.filled,
+                    maker_leaves: order.leaves(),
+                    price: trade_price,
+                    quantity: traded,
+                });
+                if order.leaves() == 0 {
+                    let id = order.id;
+                    queue.pop_front();
+                    if queue.is_empty() {
+                        self.levels(side).remove(&level);
+                    }
+                    self.orders.remove(&id);
+                }
+                touched.insert((side, level));
+            }
+            remaining -= traded;
+        }
+        self.last = Some(trade_price);
+        Some(Uncross { price: trade_price, fills, book: self.book_updates(touched) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Makers, prices and quantities of the fills of a report.
+    fn trades(fills: &[Fill]) -> Vec<(OrderId, f64, u64)> {
+        fills.iter().map(|f| (f.maker, f.price, f.quantity)).collect()
+    }
+
+    #[test]
+    fn orders_match_in_price_then_time_priority() {
+        let mut book = OrderBook::new(0.01);
+        let first = book.limit(1, Side::Sell, 10.0, 100).unwrap().order;
+        let second = book.limit(2, Side::Sell, 10.0, 100).unwrap().order;
+        let better = book.limit(3, Side::Sell, 9.99, 50).unwrap().order;
+        let report = book.market(Side::Buy, 200).unwrap();
+        assert_eq!(trades(&report.fills), vec![(better, 9.99, 50), (first, 10.0, 100), (second, 10.0, 50)]);
+        assert_eq!(report.status, Status::Filled);
+        assert_eq!(report.fills[2].maker_owner, 2);
+        assert_eq!(report.fills[2].maker_leaves, 50);
+        assert_eq!(book.best_ask(), Some((10.0, 50)));
+        assert_eq!(book.last(), Some(10.0));
+    }
+
+    #[test]
+    fn limit_order_rests_what_it_cannot_trade() {
+        let mut book = OrderBook::new(0.01);
+        book.limit(1, Side::Sell, 9.99, 50).unwrap();
+        book.limit(1, Side::Sell, 10.01, 100).unwrap();
+        let report = book.limit(2, Side::Buy, 10.0, 300).unwrap();
+        assert_eq!(report.status, Status::PartiallyFilled);
+        assert_eq!((report.filled, report.leaves), (50, 250));
+        assert_eq!(book.best_bid(), Some((10.0, 250)));
+        assert_eq!(book.best_ask(), Some((10.01, 100)));
+    }
+
+    #[test]
+    fn market_order_cancels_what_it_cannot_trade() {
+        let mut book = OrderBook::new(0.01);
+        book.limit(1, Side::Buy, 10.0, 100).unwrap();
+        let report = book.market(Side::Sell, 150).unwrap();
+        assert_eq!(report.status, Status::Cancelled);
+        assert_eq!((report.filled, report.leaves), (100, 0));
+        assert_eq!(book.best_bid(), None);
+    }
+
+    #[test]
+    fn replace_keeps_priority_only_when_the_quantity_decreases() {
+        let mut book = OrderBook::new(0.01);
+        let first = book.limit(1, Side::Buy, 10.0, 100).unwrap().order;
+        let second = book.limit(2, Side::Buy, 10.0, 100).unwrap().order;
+        assert_eq!(book.replace(first, 10.0, 60).unwrap().status, Status::Replaced);
+        assert_eq!(trades(&book.market(Side::Sell, 10).unwrap().fills), vec![(first, 10.0, 10)]);
+
+        // a larger quantity sends the order to the back of its level
+        book.replace(first, 10.0, 200).unwrap();
+        assert_eq!(trades(&book.market(Side::Sell, 10).unwrap().fills), vec![(second, 10.0, 10)]);
+
+        // so does a new price, even back to the same level
+        book.replace(second, 9.99, 90).unwrap();
+        book.replace(second, 10.0, 90).unwrap();
+        assert_eq!(trades(&book.market(Side::Sell, 10).unwrap().fills), vec![(first, 10.0, 10)]);
+    }
+
+    #[test]
+    fn replace_through_the_spread_trades() {
+        let mut book = OrderBook::new(0.01);
+        let ask = book.limit(1, Side::Sell, 10.01, 100).unwrap().order;
+        let bid = book.limit(2, Side::Buy, 10.0, 100).unwrap().order;
+        let report = book.replace(bid, 10.01, 100).unwrap();
+        assert_eq!(report.status, Status::Filled);
+        assert_eq!(trades(&report.fills), vec![(ask, 10.01, 100)]);
+        assert!(book.replace(bid, 10.0, 100).is_err());
+    }
+
+    #[test]
+    fn replace_below_the_filled_quantity_is_refused() {
+        let mut book = OrderBook::new(0.01);
+        let bid = book.limit(1, Side::Buy, 10.0, 100).unwrap().order;
+        book.market(Side::Sell, 40).unwrap();
+        assert!(book.replace(bid, 10.0, 40).is_err());
+        let report = book.replace(bid, 10.0, 50).unwrap();
+        assert_eq!((report.filled, report.leaves), (40, 10));
+    }
+
+    #[test]
+    fn prices_below_one_tick_are_refused() {
+        let mut book = OrderBook::new(0.01);
+        for price in [f64::NAN, f64::INFINITY, -10.0, 0.0, 0.004] {
+            assert!(book.limit(1, Side::Buy, price, 100).is_err(), "limit at {}", price);
+        }
+        let bid = book.limit(1, Side::Buy, 0.006, 100).unwrap();
+        assert_eq!(book.best_bid(), Some((0.01, 100)));
+        for price in [f64::NAN, f64::INFINITY, 0.004] {
+            assert!(book.replace(bid.order, price, 100).is_err(), "replace at {}", price);
+        }
+    }
+
+    #[test]
+    fn cancel_removes_the_order() {
+        let mut book = OrderBook::new(0.01);
+        let bid = book.limit(1, Side::Buy, 10.0, 100).unwrap().order;
+        let report = book.cancel(bid).unwrap();
+        assert_eq!(report.status, Status::Cancelled);
+        assert_eq!(report.book, vec![Level { side: Side::Buy, price: 10.0, quantity: 0, orders: 0 }]);
+        assert_eq!(book.best_bid(), None);
+        assert!(book.cancel(bid).is_err());
+    }
+
+    #[test]
+    fn closed_book_only_cancels() {
+        let mut book = OrderBook::new(0.01);
+        let bid = book.limit(1, Side::Buy, 10.0, 100).unwrap().order;
+        book.set_state(BookState::Closed);
+        assert!(book.limit(1, Side::Buy, 10.0, 100).is_err());
+        assert!(book.market(Side::Sell, 100).is_err());
+        assert!(book.replace(bid, 10.0, 50).is_err());
+        assert!(book.cancel(bid).is_ok());
+    }
+
+    // A book in auction with bids 10.02x100, 10.01x200, 10.00x100 and asks 9.99x150, 10.00x100, 10.01x200.
+    fn crossed_book() -> OrderBook {
+        let mut book = OrderBook::new(0.01);
+        book.set_state(BookState::Auction);
+        for (price, quantity) in [(10.02, 100), (10.01, 200), (10.0, 100)] {
+            book.limit(1, Side::Buy, price, quantity).unwrap();
+        }
+        for (price, quantity) in [(9.99, 150), (10.0, 100), (10.01, 200)] {
+            book.limit(2, Side::Sell, price, quantity).unwrap();
+        }
+        book
+    }
+
+    #[test]
+    fn auction_accumulates_orders() {
+        let mut book = OrderBook::new(0.01);
+        book.set_state(BookState::Auction);
+        book.limit(1, Side::Sell, 10.0, 100).unwrap();
+        assert_eq!(book.indicative(), None);
+        let report = book.limit(2, Side::Buy, 10.05, 100).unwrap();
+        assert!(report.fills.is_empty());
+        assert_eq!(report.leaves, 100);
+        assert!(book.market(Side::Buy, 100).is_err());
+        assert_eq!(book.indicative(), Some((10.0, 100)));
+    }
+
+    #[test]
+    fn indicative_price_executes_the_most_volume() {
+        // executable volume: 150 at 9.99, 250 at 10.00, 300 at 10.01, 100 at 10.02
+        assert_eq!(crossed_book().indicative(), Some((10.01, 300)));
+    }
+
+    #[test]
+    fn equal_volumes_go_to_the_price_nearest_the_last_trade() {
+        let mut book = OrderBook::new(0.01);
+        book.set_state(BookState::Auction);
+        book.limit(1, Side::Buy, 10.02, 100).unwrap();
+        book.limit(2, Side::Sell, 10.0, 100).unwrap();
+        assert_eq!(book.indicative(), Some((10.0, 100)));
+
+        let mut book = OrderBook::new(0.01);
+        book.limit(1, Side::Sell, 10.02, 1).unwrap();
+        book.market(Side::Buy, 1).unwrap();
+        book.set_state(BookState::Auction);
+        book.limit(1, Side::Buy, 10.02, 100).unwrap();
+        book.limit(2, Side::Sell, 10.0, 100).unwrap();
+        assert_eq!(book.indicative(), Some((10.02, 100)));
+    }
+
+    #[test]
+    fn uncross_executes_at_the_equilibrium_price() {
+        let mut book = crossed_book();
+        let uncross = book.uncross().unwrap();
+        assert_eq!(uncross.price, 10.01);
+        assert!(uncross.fills.iter().all(|f| f.price == 10.01));
+        let traded = |owner| uncross.fills.iter().filter(|f| f.maker_owner == owner).map(|f| f.quantity).sum::<u64>();
+        assert_eq!((traded(1), traded(2)), (300, 300));
+        // the last ask in price priority keeps what was not executed
+        assert_eq!(book.best_bid(), Some((10.0, 100)));
+        assert_eq!(book.best_ask(), Some((10.01, 150)));
+        assert_eq!(book.last(), Some(10.01));
+        assert_eq!(book.indicative(), None);
+        assert!(book.uncross().is_none());
+        assert_eq!(book.state(), BookState::Auction);
+    }
+}
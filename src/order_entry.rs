@@ -0,0 +1,445 @@
+// Order entry sessions of the exchange simulator.
+// They share the listener and the line framing of the market data protocol; a connection
+// opening with a logon is an order entry session. Each side numbers its messages from 1:
+//   -> {"seq":1,"type":"logon","session":"desk1"}
+//   -> {"seq":2,"type":"new_order","client_order_id":"o1","symbol":"AAPL","side":"buy","order_type":"limit","price":45.9,"quantity":100}
+//   <- {"seq":2,"type":"execution_report","client_order_id":"o1","order_id":7,"symbol":"AAPL","side":"buy","status":"new",...}
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use serde::{Deserialize, Serialize};
+
+use super::order_book::{OrderId, Owner, Report, Side, Status};
+use super::{Market, HEARTBEAT, SESSION_QUEUE};
+use crate::protocol;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderType {
+    Limit,
+    Market
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OrderMessage {
+    // client -> exchange: open the session, echoed by the exchange once accepted
+    Logon { session: String },
+    // client -> exchange: enter an order
+    NewOrder(NewOrder),
+    // client -> exchange: cancel the order entered as `orig_client_order_id`
+    Cancel { client_order_id: String, orig_client_order_id: String },
+    // client -> exchange: change the price and total quantity of an order
+    Replace { client_order_id: String, orig_client_order_id: String, price: f64, quantity: u64 },
+    // exchange -> client: new state of an order, `last_price` and `last_quantity` for a trade,
+    // `orig_client_order_id` for the reports of a cancel or replace
+    ExecutionReport {
+        client_order_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        orig_client_order_id: Option<String>,
+        order_id: OrderId,
+        symbol: String,
+        side: Side,
+        status: Status,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_price: Option<f64>,
+        last_quantity: u64,
+        filled: u64,
+        leaves: u64,
+    },
+    // exchange -> client: a message was refused
+    Reject {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ref_seq: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_order_id: Option<String>,
+        text: String,
+    },
+    // both ways: close the session, the exchange cancels the resting orders of the session
+    Logout {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        text: Option<String>,
+    },
+}
+
+// An order entered by a client, `price` is required for limit orders.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NewOrder {
+    pub client_order_id: String,
+    pub symbol: String,
+    pub side: Side,
+    pub order_type: OrderType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub price: Option<f64>,
+    pub quantity: u64,
+}
+
+// A message with its sequence number.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub message: OrderMessage,
+}
+
+// Where the executions of the orders of a session are sent, with the orders of the session
+// still known to the exchange by client order id: the exchange forgets those it fills.
+#[derive(Clone)]
+pub struct Trader {
+    pub owner: Owner,
+    pub sender: SyncSender<OrderMessage>,
+    pub orders: Arc<Mutex<HashMap<String, SessionOrder>>>,
+    pub connected: Arc<AtomicBool>, // cleared when the session does not keep up with its executions
+}
+
+impl Trader {
+    pub fn new(owner: Owner, sender: SyncSender<OrderMessage>) -> Trader {
+        Trader { owner, sender, orders: Arc::new(Mutex::new(HashMap::new())), connected: Arc::new(AtomicBool::new(true)) }
+    }
+
+    // Queue an execution report without waiting, the exchange reports while it holds the books:
+    // a session whose queue is full is disconnected and gets nothing more.
+    pub fn report(&self, message: OrderMessage) {
+        if !self.is_connected() {
+            return;
+        }
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(message) {
+            self.connected.store(false, Ordering::SeqCst);
+            eprintln!("ERROR::order entry session {} too slow, disconnected", self.owner);
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+}
+
+// An order of the session still known to the exchange.
+#[derive(Clone)]
+pub struct SessionOrder {
+    symbol: String,
+    order_id: OrderId,
+    side: Side,
+    quantity: u64,
+}
+
+// True when the first message of a connection opens an order entry session.
+pub fn is_logon(first: &serde_json::Value) -> bool {
+    first.get("type").and_then(|t| t.as_str()) == Some("logon")
+}
+
+// Execution reports of an order: the acknowledgement of the request, one report per trade
+// and a final cancellation when a market order could not be fully filled.
+pub fn execution_reports(client_order_id: &str, orig_client_order_id: Option<&str>, symbol: &str, side: Side, quantity: u64, ack: Status, report: &Report) -> Vec<OrderMessage> {
+    let traded: u64 = report.fills.iter().map(|f| f.quantity).sum();
+    let mut filled = report.filled - traded;
+    let execution = |status, filled: u64, leaves, last_price, last_quantity| OrderMessage::ExecutionReport {
+        client_order_id: client_order_id.to_string(),
+        orig_client_order_id: orig_client_order_id.map(str::to_string),
+        order_id: report.order,
+        symbol: symbol.to_string(),
+        side,
+        status,
+        last_price,
+        last_quantity,
+        filled,
+        leaves,
+    };
+    let leaves = if ack == Status::Cancelled { 0 } else { quantity - filled };
+    let mut reports = vec![execution(ack, filled, leaves, None, 0)];
+    for fill in &report.fills {
+        filled += fill.quantity;
+        let status = if filled == quantity { Status::Filled } else { Status::PartiallyFilled };
+        reports.push(execution(status, filled, quantity - filled, Some(fill.price), fill.quantity));
+    }
+    if report.status == Status::Cancelled && ack != Status::Cancelled {
+        reports.push(execution(Status::Cancelled, filled, 0, None, 0));
+    }
+    reports
+}
+
+// Serve an order entry session opened by `first`: requests are read on their own thread
+// while this one numbers and writes the rejects and the execution reports.
+pub fn handle_session(first: serde_json::Value, reader: BufReader<TcpStream>, mut stream: TcpStream, market: &Arc<Market>) {
+    let (sender, receiver) = mpsc::sync_channel(SESSION_QUEUE);
+    let logon = serde_json::from_value::<Envelope>(first);
+    let session = match logon {
+        Ok(Envelope { seq: 1, message: OrderMessage::Logon { session } }) => session,
+        other => {
+            let text = match other {
+                Ok(envelope) => format!("logon with sequence number 1 expected, got {}", envelope.seq),
+                Err(e) => e.to_string()
+            };
+            let _ = protocol::write(&mut stream, &Envelope { seq: 1, message: OrderMessage::Logout { text: Some(text) } });
+            return;
+        }
+    };
+    println!("order entry logon {}", session);
+    let trader = Trader::new(market.new_owner(), sender);
+    let _ = trader.sender.send(OrderMessage::Logon { session: session.to_string() });
+
+    let requests = Arc::clone(market);
+    let session_trader = trader.clone();
+    thread::spawn(move || {
+        let mut reader = reader;
+        read_session(&mut reader, &requests, &session_trader);
+        requests.cancel_all(session_trader.owner);
+        if !session_trader.is_connected() {
+            // the writer may wait on a client that does not read
+            let _ = reader.get_ref().shutdown(Shutdown::Both);
+        }
+    });
+    let connected = Arc::clone(&trader.connected);
+    drop(trader);
+
+    let mut seq = 0;
+    loop {
+        let message = match receiver.recv_timeout(HEARTBEAT) {
+            Ok(message) => message,
+            Err(RecvTimeoutError::Timeout) if !connected.load(Ordering::SeqCst) => {
+                OrderMessage::Logout { text: Some("executions not read in time, disconnected".to_string()) }
+            }
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break
+        };
+        seq += 1;
+        let logout = matches!(message, OrderMessage::Logout { .. });
+        if let Err(e) = protocol::write(&mut stream, &Envelope { seq, message }) {
+            eprintln!("Exchange socket error {}", e);
+            break;
+        }
+        if logout {
+            break;
+        }
+    }
+    println!("order entry logout {}", session);
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+}
+
+// Requests of a logged on session, whose orders may still rest in a book. The execution
+// reports are queued on the sender of the trader by the exchange, only rejects are returned.
+// Also used by the FIX sessions which translate its responses.
+pub struct Session<'a> {
+    market: &'a Market,
+    trader: &'a Trader,
+}
+
+impl<'a> Session<'a> {
+    pub fn new(market: &'a Market, trader: &'a Trader) -> Session<'a> {
+        Session { market, trader }
+    }
+
+    fn reject(seq: u64, client_order_id: String, text: String) -> Vec<OrderMessage> {
+        vec![OrderMessage::Reject { ref_seq: Some(seq), client_order_id: Some(client_order_id), text }]
+    }
+
+    // Order of the session entered as `client_order_id`, unless it was filled or cancelled.
+    fn order(&self, client_order_id: &str) -> Option<SessionOrder> {
+        self.trader.orders.lock().unwrap().get(client_order_id).cloned()
+    }
+
+    pub fn new_order(&mut self, seq: u64, order: NewOrder) -> Vec<OrderMessage> {
+        let NewOrder { client_order_id, symbol, side, order_type, price, quantity } = order;
+        if self.order(&client_order_id).is_some() {
+            let text = format!("duplicate client order id {}", client_order_id);
+            return Session::reject(seq, client_order_id, text);
+        }
+        let owner = self.trader.owner;
+        let orders = &self.trader.orders;
+        let acknowledge = |report: &Report| {
+            if report.leaves > 0 {
+                let order = SessionOrder { symbol: symbol.to_string(), order_id: report.order, side, quantity };
+                orders.lock().unwrap().insert(client_order_id.to_string(), order);
+            }
+            execution_reports(&client_order_id, None, &symbol, side, quantity, Status::New, report)
+        };
+        let result = match (order_type, price) {
+            (OrderType::Limit, Some(price)) => self.market.execute_order(&symbol, self.trader, &client_order_id, side, |book| book.limit(owner, side, price, quantity), acknowledge),
+            (OrderType::Limit, None) => Err("limit order without price".to_string()),
+            (OrderType::Market, _) => self.market.execute_order(&symbol, self.trader, &client_order_id, side, |book| book.market(side, quantity), acknowledge),
+        };
+        match result {
+            Ok(()) => Vec::new(),
+            Err(text) => Session::reject(seq, client_order_id, text)
+        }
+    }
+
+    pub fn cancel(&mut self, seq: u64, client_order_id: String, orig_client_order_id: String) -> Vec<OrderMessage> {
+        let Some(order) = self.order(&orig_client_order_id) else {
+            return Session::reject(seq, client_order_id, format!("order {} not found", orig_client_order_id));
+        };
+        let orders = &self.trader.orders;
+        let acknowledge = |report: &Report| {
+            orders.lock().unwrap().remove(&orig_client_order_id);
+            execution_reports(&client_order_id, Some(&orig_client_order_id), &order.symbol, order.side, order.quantity, Status::Cancelled, report)
+        };
+        let result = self.market.execute_order(&order.symbol, self.trader, &client_order_id, order.side, |book| book.cancel(order.order_id), acknowledge);
+        match result {
+            Ok(()) => Vec::new(),
+            Err(text) => Session::reject(seq, client_order_id, text)
+        }
+    }
+
+    pub fn replace(&mut self, seq: u64, client_order_id: String, orig_client_order_id: String, price: f64, quantity: u64) -> Vec<OrderMessage> {
+        let Some(order) = self.order(&orig_client_order_id) else {
+            return Session::reject(seq, client_order_id, format!("order {} not found", orig_client_order_id));
+        };
+        let orders = &self.trader.orders;
+        let acknowledge = |report: &Report| {
+            let mut orders = orders.lock().unwrap();
+            orders.remove(&orig_client_order_id);
+            if report.leaves > 0 {
+                orders.insert(client_order_id.to_string(), SessionOrder { quantity, ..order.clone() });
+            }
+            execution_reports(&client_order_id, Some(&orig_client_order_id), &order.symbol, order.side, quantity, Status::Replaced, report)
+        };
+        let result = self.market.execute_order(&order.symbol, self.trader, &client_order_id, order.side, |book| book.replace(order.order_id, price, quantity), acknowledge);
+        match result {
+            Ok(()) => Vec::new(),
+            Err(text) => Session::reject(seq, client_order_id, text)
+        }
+    }
+}
+
+// Read and serve the requests of a session until it logs out or disconnects.
+fn read_session<R: BufRead>(reader: &mut R, market: &Market, trader: &Trader) {
+    let mut expected = 2;
+    let mut session = Session::new(market, trader);
+    while trader.is_connected() {
+        let envelope = match protocol::read::<_, Envelope>(reader) {
+            Ok(Some(envelope)) => envelope,
+            Ok(None) => return,
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                // the sequence number of a malformed message is unknown, it is not consumed
+                let _ = trader.sender.send(OrderMessage::Reject { ref_seq: None, client_order_id: None, text: e.to_string() });
+                continue;
+            }
+            Err(_) => return
+        };
+        let seq = envelope.seq;
+        if seq != expected {
+            let text = format!("sequence number {} expected, got {}", expected, seq);
+            let _ = trader.sender.send(OrderMessage::Logout { text: Some(text) });
+            return;
+        }
+        expected += 1;
+        let responses = match envelope.message {
+            OrderMessage::NewOrder(order) => session.new_order(seq, order),
+            OrderMessage::Cancel { client_order_id, orig_client_order_id } => session.cancel(seq, client_order_id, orig_client_order_id),
+            OrderMessage::Replace { client_order_id, orig_client_order_id, price, quantity } => {
+                session.replace(seq, client_order_id, orig_client_order_id, price, quantity)
+            }
+            OrderMessage::Logout { .. } => {
+                let _ = trader.sender.send(OrderMessage::Logout { text: None });
+                return;
+            }
+            other => vec![OrderMessage::Reject { ref_seq: Some(seq), client_order_id: None, text: format!("unexpected message {:?}", other) }]
+        };
+        for response in responses {
+            if trader.sender.send(response).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::dictionary::Dictionary;
+
+    fn market() -> Market {
+        let dictionary: Dictionary = serde_json::from_str(r#"{"symbols": {"AAPL": {"name": "Apple", "kind": "Equity", "close": 45.97}}}"#).unwrap();
+        Market::new(dictionary, 7, None, false)
+    }
+
+    fn bid(client_order_id: &str) -> OrderMessage {
+        let symbol = "AAPL".to_string();
+        OrderMessage::NewOrder(NewOrder { client_order_id: client_order_id.to_string(), symbol, side: Side::Buy, order_type: OrderType::Limit, price: Some(40.0), quantity: 10 })
+    }
+
+    // Messages queued to a session reading `requests` with their sequence numbers, the resting
+    // orders keep the trader so the queue is drained as it stands.
+    fn serve(requests: Vec<(u64, OrderMessage)>) -> Vec<OrderMessage> {
+        let market = market();
+        let (sender, receiver) = mpsc::sync_channel(SESSION_QUEUE);
+        let trader = Trader::new(market.new_owner(), sender);
+        let mut lines = String::new();
+        for (seq, message) in requests {
+            lines += &serde_json::to_string(&Envelope { seq, message }).unwrap();
+            lines += "\n";
+        }
+        read_session(&mut Cursor::new(lines), &market, &trader);
+        receiver.try_iter().collect()
+    }
+
+    // Client order id and status of the execution reports, text of the rejects and the logouts.
+    fn summary(messages: &[OrderMessage]) -> Vec<String> {
+        messages.iter().map(|message| match message {
+            OrderMessage::ExecutionReport { client_order_id, status, .. } => format!("{} {:?}", client_order_id, status),
+            OrderMessage::Reject { ref_seq, text, .. } => format!("reject {:?} {}", ref_seq, text),
+            OrderMessage::Logout { text } => format!("logout {}", text.as_deref().unwrap_or("")),
+            other => format!("{:?}", other)
+        }).collect()
+    }
+
+    #[test]
+    fn requests_in_sequence_are_served() {
+        let cancel = OrderMessage::Cancel { client_order_id: "b".to_string(), orig_client_order_id: "a".to_string() };
+        let messages = serve(vec![(2, bid("a")), (3, cancel), (4, OrderMessage::Logout { text: None })]);
+        assert_eq!(summary(&messages), vec!["a New", "b Cancelled", "logout "]);
+    }
+
+    #[test]
+    fn sequence_gap_logs_out() {
+        let messages = serve(vec![(2, bid("a")), (4, bid("b"))]);
+        assert_eq!(summary(&messages), vec!["a New", "logout sequence number 3 expected, got 4"]);
+    }
+
+    #[test]
+    fn repeated_sequence_number_logs_out() {
+        let messages = serve(vec![(2, bid("a")), (2, bid("b"))]);
+        assert_eq!(summary(&messages), vec!["a New", "logout sequence number 3 expected, got 2"]);
+    }
+
+    #[test]
+    fn duplicate_client_order_id_is_rejected() {
+        let messages = serve(vec![(2, bid("a")), (3, bid("a"))]);
+        assert_eq!(summary(&messages), vec!["a New", "reject Some(3) duplicate client order id a"]);
+    }
+
+    #[test]
+    fn malformed_message_does_not_consume_a_sequence_number() {
+        let market = market();
+        let (sender, receiver) = mpsc::sync_channel(SESSION_QUEUE);
+        let trader = Trader::new(market.new_owner(), sender);
+        let order = serde_json::to_string(&Envelope { seq: 2, message: bid("a") }).unwrap();
+        read_session(&mut Cursor::new(format!("{{\"seq\": 2}}\n{}\n", order)), &market, &trader);
+        let messages: Vec<OrderMessage> = receiver.try_iter().collect();
+        assert!(matches!(messages[0], OrderMessage::Reject { ref_seq: None, .. }));
+        assert_eq!(summary(&messages[1..]), vec!["a New"]);
+    }
+
+    #[test]
+    fn slow_trader_is_disconnected() {
+        let market = market();
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let trader = Trader::new(market.new_owner(), sender);
+        trader.report(OrderMessage::Logout { text: None });
+        assert!(trader.is_connected());
+        trader.report(OrderMessage::Logout { text: None });
+        assert!(!trader.is_connected());
+        // nothing more is queued nor read once disconnected
+        receiver.recv().unwrap();
+        trader.report(OrderMessage::Logout { text: None });
+        let order = serde_json::to_string(&Envelope { seq: 2, message: bid("a") }).unwrap();
+        read_session(&mut Cursor::new(order + "\n"), &market, &trader);
+        assert!(receiver.try_recv().is_err());
+        assert!(trader.orders.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,136 @@
+// Patterns selecting instruments for a subscription:
+//   AAPL          the symbol itself
+//   *=  EUR*      a glob, `*` matches any run of characters and `?` a single one
+//   kind:Equity   every instrument of a kind, also written Kind::Equity
+//   re:^[A-Z]+=$  a regular expression on the symbol
+use std::str::FromStr;
+use regex::Regex;
+
+use crate::instrument::Instrument;
+
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Symbol(String),
+    Glob(String),
+    Kind(String),
+    Regex(Regex),
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        if let Some(kind) = pattern.strip_prefix("kind:").or_else(|| pattern.strip_prefix("Kind::")) {
+            match kind {
+                "Equity" | "Bond" | "Warrant" | "Currency" => Ok(Pattern::Kind(kind.to_string())),
+                _ => Err(format!("kind should be 'Equity', 'Bond', 'Warrant' or 'Currency', but is '{}'", kind))
+            }
+        } else if let Some(regex) = pattern.strip_prefix("re:") {
+            Regex::new(regex).map(Pattern::Regex).map_err(|e| format!("bad regular expression '{}'::{}", regex, e))
+        } else if pattern.contains(['*', '?']) {
+            Ok(Pattern::Glob(pattern.to_string()))
+        } else {
+            Ok(Pattern::Symbol(pattern.to_string()))
+        }
+    }
+}
+
+impl Pattern {
+    // True when the instrument is selected by the pattern.
+    pub fn matches(&self, instrument: &Instrument) -> bool {
+        let symbol = instrument.get_name();
+        match self {
+            Pattern::Symbol(s) => s == symbol,
+            Pattern::Glob(glob) => glob_matches(glob.as_bytes(), symbol.as_bytes()),
+            Pattern::Kind(kind) => instrument.get_kind().name() == kind,
+            Pattern::Regex(regex) => regex.is_match(symbol),
+        }
+    }
+
+    // The pattern as it was written, without the `Kind::` spelling.
+    pub fn text(&self) -> String {
+        match self {
+            Pattern::Symbol(s) | Pattern::Glob(s) => s.to_string(),
+            Pattern::Kind(kind) => format!("kind:{}", kind),
+            Pattern::Regex(regex) => format!("re:{}", regex.as_str()),
+        }
+    }
+}
+
+// Match `text` against a glob, backtracking to the last `*` on a mismatch.
+fn glob_matches(glob: &[u8], text: &[u8]) -> bool {
+    let (mut g, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None; // position of the last `*` and of the text it resumes from
+    while t < text.len() {
+        match glob.get(g) {
+            Some(b'*') => {
+                star = Some((g, t));
+                g += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                g += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_g, star_t)) => {
+                    g = star_g + 1;
+                    t = star_t + 1;
+                    star = Some((star_g, star_t + 1));
+                }
+                None => return false
+            }
+        }
+    }
+    glob[g..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrument::Kind;
+
+    fn glob(glob: &str, text: &str) -> bool {
+        glob_matches(glob.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn globs_match_runs_and_single_characters() {
+        assert!(glob("*=", "EUR="));
+        assert!(glob("EUR*", "EUR="));
+        assert!(glob("EUR*", "EUR"));
+        assert!(glob("*", ""));
+        assert!(glob("A?PL", "AAPL"));
+        assert!(glob("*.L", "VOD.L"));
+        assert!(glob("A*B*C", "AxxBxBxC"));
+        assert!(glob("**A", "BA"));
+        assert!(!glob("*=", "EURUSD"));
+        assert!(!glob("A?PL", "APL"));
+        assert!(!glob("A*B*C", "AxxBxBx"));
+        assert!(!glob("", "A"));
+        assert!(!glob("AAPL", "AAP"));
+    }
+
+    #[test]
+    fn patterns_parse_by_prefix() {
+        assert!(matches!("AAPL".parse(), Ok(Pattern::Symbol(s)) if s == "AAPL"));
+        assert!(matches!("EUR*".parse(), Ok(Pattern::Glob(s)) if s == "EUR*"));
+        assert!(matches!("Kind::Bond".parse(), Ok(Pattern::Kind(s)) if s == "Bond"));
+        assert_eq!("Kind::Bond".parse::<Pattern>().unwrap().text(), "kind:Bond");
+        assert!("kind:Stock".parse::<Pattern>().is_err());
+        assert!("re:[A-".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn patterns_select_instruments() {
+        let eur = Instrument::new(Kind::Currency("EUR=".to_string()));
+        let aapl = Instrument::new(Kind::Equity("AAPL".to_string()));
+        let selected = |pattern: &str| {
+            let pattern: Pattern = pattern.parse().unwrap();
+            [&eur, &aapl].into_iter().filter(|i| pattern.matches(i)).map(|i| i.get_name().to_string()).collect::<Vec<_>>()
+        };
+        assert_eq!(selected("*="), ["EUR="]);
+        assert_eq!(selected("kind:Equity"), ["AAPL"]);
+        assert_eq!(selected("re:^[A-Z]+$"), ["AAPL"]);
+        assert_eq!(selected("AAPL"), ["AAPL"]);
+        assert_eq!(selected("MSFT"), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,198 @@
+// Stochastic price processes used to drive instrument prices.
+use std::fmt::Debug;
+use rand::RngCore;
+use rand_distr::{Distribution, Poisson, StandardNormal};
+
+use crate::instrument::Kind;
+
+// Length of a trading day in seconds (8h30), volatilities are expressed per day.
+pub const TRADING_DAY_SECS: f64 = 30600.0;
+
+// A price process evolves a price over time, `dt` is expressed in trading days.
+pub trait PriceProcess: Debug + Send {
+    // Advance the process by `dt` and return the new price.
+    fn step(&mut self, rng: &mut dyn RngCore, dt: f64) -> f64;
+}
+
+// Draw a standard normal variate.
+fn normal(rng: &mut dyn RngCore) -> f64 {
+    StandardNormal.sample(rng)
+}
+
+// Geometric Brownian motion: dS = mu.S.dt + sigma.S.dW
+#[derive(Debug)]
+pub struct GeometricBrownian {
+    price: f64,
+    drift: f64,
+    sigma: f64,
+}
+
+impl GeometricBrownian {
+    pub fn new(price: f64, drift: f64, sigma: f64) -> GeometricBrownian {
+        GeometricBrownian { price, drift, sigma }
+    }
+}
+
+impl PriceProcess for GeometricBrownian {
+    fn step(&mut self, rng: &mut dyn RngCore, dt: f64) -> f64 {
+        let z = normal(rng);
+        self.price *= ((self.drift - 0.5 * self.sigma * self.sigma) * dt + self.sigma * dt.sqrt() * z).exp();
+        self.price
+    }
+}
+
+// Ornstein-Uhlenbeck process on the log price, mean reverting toward `mean`.
+// Suited for currencies which oscillate around a level.
+#[derive(Debug)]
+pub struct OrnsteinUhlenbeck {
+    price: f64,
+    mean: f64,
+    speed: f64,
+    sigma: f64,
+}
+
+impl OrnsteinUhlenbeck {
+    pub fn new(price: f64, mean: f64, speed: f64, sigma: f64) -> OrnsteinUhlenbeck {
+        OrnsteinUhlenbeck { price, mean, speed, sigma }
+    }
+}
+
+impl PriceProcess for OrnsteinUhlenbeck {
+    fn step(&mut self, rng: &mut dyn RngCore, dt: f64) -> f64 {
+        // exact discretisation of the OU process
+        let x = self.price.ln();
+        let m = self.mean.ln();
+        let decay = (-self.speed * dt).exp();
+        let sd = self.sigma * ((1.0 - decay * decay) / (2.0 * self.speed)).sqrt();
+        let x = m + (x - m) * decay + sd * normal(rng);
+        self.price = x.exp();
+        self.price
+    }
+}
+
+// Merton jump-diffusion: a geometric Brownian motion with log-normal jumps
+// arriving as a Poisson process of `intensity` jumps per day.
+#[derive(Debug)]
+pub struct JumpDiffusion {
+    diffusion: GeometricBrownian,
+    intensity: f64,
+    jump_mean: f64,
+    jump_sigma: f64,
+}
+
+impl JumpDiffusion {
+    pub fn new(price: f64, drift: f64, sigma: f64, intensity: f64, jump_mean: f64, jump_sigma: f64) -> JumpDiffusion {
+        // compensate the drift so jumps do not bias the expected return
+        let k = (jump_mean + 0.5 * jump_sigma * jump_sigma).exp() - 1.0;
+        JumpDiffusion {
+            diffusion: GeometricBrownian::new(price, drift - intensity * k, sigma),
+            intensity,
+            jump_mean,
+            jump_sigma,
+        }
+    }
+}
+
+impl PriceProcess for JumpDiffusion {
+    fn step(&mut self, rng: &mut dyn RngCore, dt: f64) -> f64 {
+        self.diffusion.step(rng, dt);
+        let lambda = self.intensity * dt;
+        if lambda > 0.0 {
+            let jumps: f64 = Poisson::new(lambda).unwrap().sample(rng);
+            for _ in 0..jumps as usize {
+                self.diffusion.price *= (self.jump_mean + self.jump_sigma * normal(rng)).exp();
+            }
+        }
+        self.diffusion.price
+    }
+}
+
+// Build a process by model name ("gbm", "ou" or "jump") seeded from a reference
+// close and a daily volatility.
+pub fn by_name(model: &str, close: f64, volatility: f64) -> Result<Box<dyn PriceProcess>, String> {
+    match model {
+        "gbm" => Ok(Box::new(GeometricBrownian::new(close, 0.0, volatility))),
+        "ou" => Ok(Box::new(OrnsteinUhlenbeck::new(close, close, 2.0, volatility))),
+        "jump" => Ok(Box::new(JumpDiffusion::new(close, 0.0, volatility, 0.5, 0.0, 4.0 * volatility))),
+        _ => Err(format!("unknown price model '{}', should be 'gbm', 'ou' or 'jump'", model))
+    }
+}
+
+// Default model for each kind of instrument.
+pub fn default_model(kind: &Kind) -> &'static str {
+    match kind {
+        Kind::Equity(_) => "gbm",
+        Kind::Bond(_) => "ou",
+        Kind::Warrant(_) => "jump",
+        Kind::Currency(_) => "ou",
+    }
+}
+
+// Half of the bid/ask spread, relative to the price.
+pub fn half_spread(kind: &Kind) -> f64 {
+    match kind {
+        Kind::Equity(_) => 0.0005,
+        Kind::Bond(_) => 0.001,
+        Kind::Warrant(_) => 0.005,
+        Kind::Currency(_) => 0.0001,
+    }
+}
+
+// Price increment of each kind of instrument.
+pub fn tick_size(kind: &Kind) -> f64 {
+    match kind {
+        Kind::Equity(_) => 0.01,
+        Kind::Bond(_) => 0.01,
+        Kind::Warrant(_) => 0.001,
+        Kind::Currency(_) => 0.0001,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    // Prices of `steps` steps of `dt` trading days.
+    fn path(process: &mut dyn PriceProcess, seed: u64, steps: usize, dt: f64) -> Vec<f64> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..steps).map(|_| process.step(&mut rng, dt)).collect()
+    }
+
+    #[test]
+    fn gbm_stays_positive() {
+        // a year of minutes at a daily volatility of 50%
+        let prices = path(&mut GeometricBrownian::new(10.0, 0.0, 0.5), 7, 250 * 510, 60.0 / TRADING_DAY_SECS);
+        assert!(prices.iter().all(|p| p.is_finite() && *p > 0.0));
+    }
+
+    #[test]
+    fn ou_reverts_to_its_mean() {
+        let mut process = OrnsteinUhlenbeck::new(200.0, 100.0, 2.0, 0.01);
+        let prices = path(&mut process, 7, 50, 0.1);
+        // five days at a speed of 2 leave e^-10 of the distance to the mean
+        assert!((prices[49] / 100.0 - 1.0).abs() < 0.01, "{}", prices[49]);
+        let prices = path(&mut process, 8, 10_000, 0.1);
+        let mean = prices.iter().sum::<f64>() / prices.len() as f64;
+        assert!((mean / 100.0 - 1.0).abs() < 0.01, "{}", mean);
+    }
+
+    #[test]
+    fn jumps_move_the_price_apart_from_the_diffusion() {
+        let diffusion = path(&mut GeometricBrownian::new(10.0, 0.0, 0.02), 7, 100, 1.0);
+        let jumps = path(&mut JumpDiffusion::new(10.0, 0.0, 0.02, 0.5, 0.0, 0.08), 7, 100, 1.0);
+        assert!(jumps.iter().all(|p| p.is_finite() && *p > 0.0));
+        assert_ne!(diffusion, jumps);
+    }
+
+    #[test]
+    fn models_are_built_by_name() {
+        for model in ["gbm", "ou", "jump"] {
+            let mut first = by_name(model, 45.97, 0.02).unwrap();
+            let mut second = by_name(model, 45.97, 0.02).unwrap();
+            assert_eq!(path(first.as_mut(), 7, 20, 0.01), path(second.as_mut(), 7, 20, 0.01));
+        }
+        assert!(by_name("heston", 45.97, 0.02).is_err());
+    }
+}
@@ -0,0 +1,332 @@
+// Market data wire protocol between the exchange simulator and its clients.
+// Every message is a JSON object on its own line, tagged by its "type", the order
+// entry sessions use the same framing:
+//   {"type":"subscribe","symbol":"AAPL"}
+//   {"type":"snapshot","symbol":"AAPL","quote":{"last":45.97,...}}
+use std::io::{self, BufRead, Read, Write};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::session::Phase;
+
+// Prices of an instrument as published by the exchange.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct Quote {
+    pub last: f64,
+    pub bid: f64,
+    pub ask: f64,
+    pub open: f64,
+    pub close: f64,
+    pub tick: usize,
+    #[serde(flatten)]
+    pub prints: Prints,
+}
+
+// Condition of a trade: a regular trade, or the print of an auction.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    #[default]
+    Regular,
+    OpeningAuction,
+    ClosingAuction,
+}
+
+impl Condition {
+    // Condition of a trade printing `auction`, regular when none prints.
+    pub fn of(auction: Option<Phase>) -> Condition {
+        match auction {
+            Some(Phase::OpeningAuction) => Condition::OpeningAuction,
+            Some(Phase::ClosingAuction) => Condition::ClosingAuction,
+            _ => Condition::Regular
+        }
+    }
+}
+
+// The last trade of an instrument and the trades of its session so far.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(default)]
+pub struct Prints {
+    pub size: u64,            // size of the last trade
+    pub condition: Condition, // condition of the last trade
+    pub volume: u64,          // volume traded in the session
+    pub vwap: f64,            // average price of the session weighted by the volume
+    pub trades: usize,        // number of trades in the session
+}
+
+impl Prints {
+    // Add a trade of `size` at `price`, the opening auction starting a new session.
+    pub fn trade(&mut self, price: f64, size: u64, condition: Condition) {
+        if condition == Condition::OpeningAuction {
+            *self = Prints::default();
+        }
+        if size > 0 {
+            self.vwap = (self.vwap * self.volume as f64 + price * size as f64) / (self.volume + size) as f64;
+        }
+        self.size = size;
+        self.condition = condition;
+        self.volume += size;
+        self.trades += 1;
+    }
+}
+
+// Side of a price level in the depth of a symbol.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BookSide {
+    Bid,
+    Ask
+}
+
+// Quantity resting at a price and the number of orders making it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct Level {
+    pub price: f64,
+    pub size: u64,
+    pub orders: usize,
+}
+
+// Price levels of both sides of a symbol, best price first.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Depth {
+    pub bids: Vec<Level>,
+    pub asks: Vec<Level>,
+}
+
+// New state of one price level, a size of 0 removes the level.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct DepthChange {
+    pub side: BookSide,
+    pub price: f64,
+    pub size: u64,
+    pub orders: usize,
+}
+
+impl Depth {
+    fn levels(&mut self, side: BookSide) -> &mut Vec<Level> {
+        match side {
+            BookSide::Bid => &mut self.bids,
+            BookSide::Ask => &mut self.asks,
+        }
+    }
+
+    // Apply an incremental change, keeping the levels sorted best price first.
+    pub fn apply(&mut self, change: &DepthChange) {
+        let better = |a: f64, b: f64| if change.side == BookSide::Bid { a > b } else { a < b };
+        let levels = self.levels(change.side);
+        let position = levels.iter().position(|l| !better(l.price, change.price));
+        match position {
+            Some(i) if levels[i].price == change.price => {
+                if change.size == 0 {
+                    levels.remove(i);
+                } else {
+                    levels[i] = Level { price: change.price, size: change.size, orders: change.orders };
+                }
+            }
+            _ if change.size == 0 => {}
+            Some(i) => levels.insert(i, Level { price: change.price, size: change.size, orders: change.orders }),
+            None => levels.push(Level { price: change.price, size: change.size, orders: change.orders }),
+        }
+    }
+
+    // Changes turning this depth into `other`.
+    pub fn changes(&self, other: &Depth) -> Vec<DepthChange> {
+        let mut changes = Vec::new();
+        for (side, old, new) in [(BookSide::Bid, &self.bids, &other.bids), (BookSide::Ask, &self.asks, &other.asks)] {
+            for level in old.iter().filter(|l| !new.iter().any(|n| n.price == l.price)) {
+                changes.push(DepthChange { side, price: level.price, size: 0, orders: 0 });
+            }
+            for level in new.iter().filter(|n| !old.contains(n)) {
+                changes.push(DepthChange { side, price: level.price, size: level.size, orders: level.orders });
+            }
+        }
+        changes
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    // client -> exchange: start streaming a symbol, answered by a snapshot or an error
+    Subscribe { symbol: String },
+    // client -> exchange: stop streaming a symbol
+    Unsubscribe { symbol: String },
+    // exchange -> client: full image of a symbol, with all the levels of its book
+    Snapshot {
+        symbol: String,
+        quote: Quote,
+        #[serde(default)]
+        depth: Depth,
+    },
+    // exchange -> client: new prices of a subscribed symbol and the levels of its book that changed
+    Update {
+        symbol: String,
+        quote: Quote,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        depth: Vec<DepthChange>,
+    },
+    // exchange -> client: trading phase of a subscribed symbol when it changes, and during an auction
+    // each time an order changes it, with the price and the volume it would uncross at so far
+    Indicative {
+        symbol: String,
+        phase: Phase,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        price: Option<f64>,
+        volume: u64,
+    },
+    // exchange -> client: sent when the connection is idle, `time` in seconds of simulation
+    Heartbeat { time: f64 },
+    // exchange -> client: a request could not be served
+    Error {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        symbol: Option<String>,
+        text: String,
+    },
+}
+
+// Write one message followed by a newline.
+pub fn write<W: Write, T: Serialize>(writer: &mut W, message: &T) -> io::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    writer.flush()
+}
+
+// Longest line read, a snapshot of a deep book fits well within it.
+const MAX_LINE_LENGTH: u64 = 1024 * 1024;
+
+// Read the next message, `None` when the peer closed the connection.
+// Blank lines are skipped, malformed lines are reported as `InvalidData` errors, and so are
+// lines above `MAX_LINE_LENGTH`, which are skipped without being kept in memory.
+pub fn read<R: BufRead, T: DeserializeOwned>(reader: &mut R) -> io::Result<Option<T>> {
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.by_ref().take(MAX_LINE_LENGTH).read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        if line.len() as u64 == MAX_LINE_LENGTH && line.last() != Some(&b'\n') {
+            skip_line(reader)?;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("line longer than {} bytes", MAX_LINE_LENGTH)));
+        }
+        if !line.iter().all(u8::is_ascii_whitespace) {
+            break;
+        }
+    }
+    serde_json::from_slice(&line)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Consume the rest of the current line.
+fn skip_line<R: BufRead>(reader: &mut R) -> io::Result<()> {
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        match buffer.iter().position(|&b| b == b'\n') {
+            Some(i) => {
+                reader.consume(i + 1);
+                return Ok(());
+            }
+            None => {
+                let length = buffer.len();
+                reader.consume(length);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn level(price: f64, size: u64) -> Level {
+        Level { price, size, orders: 1 }
+    }
+
+    fn change(side: BookSide, price: f64, size: u64) -> DepthChange {
+        DepthChange { side, price, size, orders: if size > 0 { 1 } else { 0 } }
+    }
+
+    #[test]
+    fn prints_weigh_the_average_price_by_volume() {
+        let mut prints = Prints::default();
+        prints.trade(10.0, 100, Condition::Regular);
+        prints.trade(11.0, 300, Condition::Regular);
+        prints.trade(12.0, 0, Condition::ClosingAuction);
+        assert_eq!((prints.volume, prints.trades, prints.vwap), (400, 3, 10.75));
+        assert_eq!((prints.size, prints.condition), (0, Condition::ClosingAuction));
+        // the opening auction starts the next session
+        prints.trade(9.0, 50, Condition::OpeningAuction);
+        prints.trade(9.6, 150, Condition::Regular);
+        assert_eq!((prints.volume, prints.trades), (200, 2));
+        assert!((prints.vwap - 9.45).abs() < 1e-12);
+    }
+
+    #[test]
+    fn changes_keep_the_levels_best_price_first() {
+        let mut depth = Depth::default();
+        for (price, size) in [(10.0, 100), (10.2, 50), (9.8, 30)] {
+            depth.apply(&change(BookSide::Bid, price, size));
+            depth.apply(&change(BookSide::Ask, price + 1.0, size));
+        }
+        assert_eq!(depth.bids, vec![level(10.2, 50), level(10.0, 100), level(9.8, 30)]);
+        assert_eq!(depth.asks, vec![level(10.8, 30), level(11.0, 100), level(11.2, 50)]);
+        depth.apply(&change(BookSide::Bid, 10.0, 70));
+        depth.apply(&change(BookSide::Bid, 10.2, 0));
+        depth.apply(&change(BookSide::Ask, 12.0, 0));
+        assert_eq!(depth.bids, vec![level(10.0, 70), level(9.8, 30)]);
+        assert_eq!(depth.asks.len(), 3);
+    }
+
+    #[test]
+    fn changes_turn_a_depth_into_another() {
+        let old = Depth { bids: vec![level(10.0, 100), level(9.9, 20)], asks: vec![level(10.1, 40)] };
+        let new = Depth { bids: vec![level(10.0, 60), level(9.8, 10)], asks: vec![level(10.1, 40), level(10.2, 5)] };
+        let changes = old.changes(&new);
+        assert_eq!(changes.len(), 4);
+        let mut depth = old.clone();
+        for change in &changes {
+            depth.apply(change);
+        }
+        assert_eq!(depth, new);
+        assert!(new.changes(&new).is_empty());
+    }
+
+    #[test]
+    fn messages_round_trip_as_lines() {
+        let quote = Quote { last: 45.97, bid: 45.96, ask: 45.98, tick: 3, ..Quote::default() };
+        let messages = vec![
+            Message::Subscribe { symbol: "AAPL".to_string() },
+            Message::Snapshot { symbol: "AAPL".to_string(), quote, depth: Depth { bids: vec![level(45.96, 100)], asks: vec![] } },
+            Message::Update { symbol: "AAPL".to_string(), quote, depth: vec![change(BookSide::Ask, 45.98, 0)] },
+            Message::Indicative { symbol: "AAPL".to_string(), phase: Phase::OpeningAuction, price: None, volume: 0 },
+            Message::Heartbeat { time: 1.5 },
+            Message::Error { symbol: None, text: "AAPL instrument not found".to_string() },
+        ];
+        let mut lines = Vec::new();
+        for message in &messages {
+            write(&mut lines, message).unwrap();
+            lines.extend(b"\n  \n");
+        }
+        let mut reader = Cursor::new(lines);
+        for message in messages {
+            assert_eq!(read::<_, Message>(&mut reader).unwrap(), Some(message));
+        }
+        assert_eq!(read::<_, Message>(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn malformed_and_long_lines_are_skipped() {
+        let heartbeat = "{\"type\":\"heartbeat\",\"time\":2.0}\n";
+        let long = format!("{{\"type\":\"error\",\"text\":\"{}\"}}\n", "x".repeat(MAX_LINE_LENGTH as usize));
+        let mut reader = Cursor::new(format!("{{\"type\":\"nope\"}}\n{}{}{}", heartbeat, long, heartbeat));
+        assert_eq!(read::<_, Message>(&mut reader).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read::<_, Message>(&mut reader).unwrap(), Some(Message::Heartbeat { time: 2.0 }));
+        assert_eq!(read::<_, Message>(&mut reader).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read::<_, Message>(&mut reader).unwrap(), Some(Message::Heartbeat { time: 2.0 }));
+    }
+}
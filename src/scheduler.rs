@@ -0,0 +1,37 @@
+// Deterministic event scheduler ordering instrument updates on a simulated timeline.
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::time::Duration;
+
+// Events are ordered by time, then by the index of the instrument so that two
+// events falling at the same time are always delivered in the same order.
+#[derive(Default)]
+pub struct Scheduler {
+    events: BinaryHeap<Reverse<(Duration, usize)>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            events: BinaryHeap::new(),
+        }
+    }
+
+    // Schedule an event for instrument `index` at time `at` from the start of the simulation.
+    pub fn schedule(&mut self, at: Duration, index: usize) {
+        self.events.push(Reverse((at, index)));
+    }
+
+    // Pop the next event, the earliest one first.
+    pub fn next_event(&mut self) -> Option<(Duration, usize)> {
+        self.events.pop().map(|Reverse(event)| event)
+    }
+}
+
+// Stable FNV-1a hash used to derive a per instrument seed from its name,
+// unlike the std hasher it does not change between runs or releases.
+pub fn stable_hash(name: &str) -> u64 {
+    name.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
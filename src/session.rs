@@ -0,0 +1,254 @@
+// Trading sessions: the phases of a trading day for each kind of instrument and the days
+// the markets are closed. The schedules of the dictionary replace the defaults of their kind:
+//   "sessions": {"Equity": {"pre_open": "08:00:00", "opening_auction": "08:50:00", "continuous": "09:00:00",
+//                           "closing_auction": "17:30:00", "close": "17:35:00", "holidays": ["2024-12-25"]}}
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Weekday};
+use serde::{Deserialize, Serialize};
+
+use crate::instrument::Kind;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    PreOpen,        // orders may be entered, nothing trades
+    OpeningAuction, // orders are collected for the opening print
+    Continuous,     // orders trade as they arrive
+    ClosingAuction, // orders are collected for the closing print
+    Closed
+}
+
+impl Phase {
+    // The auction printing when a symbol moves from the phase `from` to `to`:
+    // the opening auction when trading starts, the closing auction when it stops.
+    pub fn auction_print(from: Phase, to: Phase) -> Option<Phase> {
+        match (from, to) {
+            (Phase::Continuous, Phase::Continuous) => None,
+            (_, Phase::Continuous) => Some(Phase::OpeningAuction),
+            (Phase::Continuous | Phase::ClosingAuction, Phase::Closed) => Some(Phase::ClosingAuction),
+            _ => None
+        }
+    }
+}
+
+// Times of the phase changes of a trading day, in the order of the day.
+// An auction of no length prints at the last price.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Schedule {
+    pub pre_open: NaiveTime,
+    pub opening_auction: NaiveTime,
+    pub continuous: NaiveTime,
+    pub closing_auction: NaiveTime,
+    pub close: NaiveTime,
+}
+
+fn time(hour: u32, minute: u32) -> NaiveTime {
+    NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+}
+
+impl Schedule {
+    // Default schedule of each kind of instrument, currencies trade continuously from midnight
+    // and close at 22:00 without auctions, when they roll to the next day.
+    pub fn of(kind: &Kind) -> Schedule {
+        let (pre_open, opening_auction, continuous, closing_auction, close) = match kind {
+            Kind::Equity(_) => (time(8, 0), time(8, 50), time(9, 0), time(17, 30), time(17, 35)),
+            Kind::Bond(_) => (time(8, 0), time(8, 55), time(9, 0), time(17, 25), time(17, 30)),
+            Kind::Warrant(_) => (time(8, 0), time(8, 55), time(9, 5), time(17, 30), time(17, 30)),
+            Kind::Currency(_) => (time(0, 0), time(0, 0), time(0, 0), time(22, 0), time(22, 0)),
+        };
+        Schedule { pre_open, opening_auction, continuous, closing_auction, close }
+    }
+
+    // The phase changes of the day, each with the phase it starts.
+    fn changes(&self) -> [(NaiveTime, Phase); 5] {
+        [
+            (self.pre_open, Phase::PreOpen),
+            (self.opening_auction, Phase::OpeningAuction),
+            (self.continuous, Phase::Continuous),
+            (self.closing_auction, Phase::ClosingAuction),
+            (self.close, Phase::Closed),
+        ]
+    }
+
+    // Phase of a trading day at `time`, a phase of no length is skipped.
+    pub fn phase(&self, time: NaiveTime) -> Phase {
+        self.changes().iter().rev()
+            .find(|(at, _)| *at <= time)
+            .map_or(Phase::Closed, |(_, phase)| *phase)
+    }
+}
+
+// The trading days of a kind of instrument: every day but the weekends and the holidays.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Calendar {
+    #[serde(flatten)]
+    pub schedule: Schedule,
+    #[serde(default)]
+    pub holidays: BTreeSet<NaiveDate>,
+}
+
+// Number of days searched for the next trading day.
+const MAX_CLOSED_DAYS: usize = 366;
+
+impl Calendar {
+    pub fn new(schedule: Schedule) -> Calendar {
+        Calendar { schedule, holidays: BTreeSet::new() }
+    }
+
+    pub fn is_trading_day(&self, date: NaiveDate) -> bool {
+        !matches!(date.weekday(), Weekday::Sat | Weekday::Sun) && !self.holidays.contains(&date)
+    }
+
+    pub fn phase(&self, at: NaiveDateTime) -> Phase {
+        if self.is_trading_day(at.date()) {
+            self.schedule.phase(at.time())
+        } else {
+            Phase::Closed
+        }
+    }
+
+    // Time and phase of the first phase change after `at`,
+    // `None` when no trading day comes within a year.
+    pub fn next_change(&self, at: NaiveDateTime) -> Option<(NaiveDateTime, Phase)> {
+        let current = self.phase(at);
+        at.date().iter_days()
+            .take(MAX_CLOSED_DAYS)
+            .filter(|date| self.is_trading_day(*date))
+            .flat_map(|date| self.schedule.changes().map(|(time, _)| date.and_time(time)))
+            .filter(|change| *change > at)
+            .map(|change| (change, self.phase(change)))
+            .find(|(_, phase)| *phase != current)
+    }
+}
+
+// Calendars of the kinds of instruments, following a simulation started at `start`.
+#[derive(Debug, Clone)]
+pub struct Sessions {
+    start: NaiveDateTime,
+    calendars: HashMap<String, Calendar>, // by kind name
+}
+
+// Names of the kinds of instruments, as written in a dictionary.
+const KINDS: [&str; 4] = ["Equity", "Bond", "Warrant", "Currency"];
+
+impl Sessions {
+    // Sessions starting at `start`, `calendars` replace the default calendars of their kind.
+    pub fn new(start: NaiveDateTime, calendars: &HashMap<String, Calendar>) -> Sessions {
+        let mut calendars = calendars.clone();
+        for name in KINDS {
+            let kind = Kind::parse(name, String::new()).unwrap();
+            calendars.entry(name.to_string()).or_insert_with(|| Calendar::new(Schedule::of(&kind)));
+        }
+        Sessions { start, calendars }
+    }
+
+    fn calendar(&self, kind: &Kind) -> &Calendar {
+        &self.calendars[kind.name()]
+    }
+
+    // Date and time after `elapsed` of simulation.
+    pub fn time(&self, elapsed: Duration) -> NaiveDateTime {
+        self.start + TimeDelta::from_std(elapsed).unwrap()
+    }
+
+    // Phase of a kind of instrument after `elapsed` of simulation.
+    pub fn phase(&self, kind: &Kind, elapsed: Duration) -> Phase {
+        self.calendar(kind).phase(self.time(elapsed))
+    }
+
+    // Simulation time of the next phase change of a kind of instrument after `elapsed`.
+    pub fn next_change(&self, kind: &Kind, elapsed: Duration) -> Option<Duration> {
+        let (change, _) = self.calendar(kind).next_change(self.time(elapsed))?;
+        (change - self.start).to_std().ok()
+    }
+
+    // Simulation time of the next event of a symbol otherwise due at `next`: it does not update
+    // past the next phase change, and waits for it outside of continuous trading.
+    // `None` when it will not trade again.
+    pub fn next_event(&self, kind: &Kind, elapsed: Duration, next: Duration) -> Option<Duration> {
+        let change = self.next_change(kind, elapsed);
+        if self.phase(kind, elapsed) == Phase::Continuous {
+            Some(change.map_or(next, |change| next.min(change)))
+        } else {
+            change
+        }
+    }
+}
+
+// Parse the start of a simulation like 2024-03-04T08:45, seconds may be given.
+pub fn parse_start(start: &str) -> Result<NaiveDateTime, String> {
+    NaiveDateTime::parse_from_str(start, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(start, "%Y-%m-%dT%H:%M"))
+        .map_err(|e| format!("start should be like 2024-03-04T08:45, but is '{}'::{}", start, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(date: &str) -> NaiveDateTime {
+        parse_start(date).unwrap()
+    }
+
+    fn equity() -> Kind {
+        Kind::parse("Equity", "AAPL".to_string()).unwrap()
+    }
+
+    #[test]
+    fn phases_follow_the_schedule_of_the_kind() {
+        let schedule = Schedule::of(&equity());
+        let phases: Vec<Phase> = [(7, 59), (8, 0), (8, 50), (9, 0), (17, 30), (17, 35)].iter()
+            .map(|&(hour, minute)| schedule.phase(time(hour, minute)))
+            .collect();
+        assert_eq!(phases, vec![Phase::Closed, Phase::PreOpen, Phase::OpeningAuction, Phase::Continuous, Phase::ClosingAuction, Phase::Closed]);
+        // the closing auction of a warrant has no length
+        let warrant = Schedule::of(&Kind::parse("Warrant", String::new()).unwrap());
+        assert_eq!(warrant.phase(time(17, 29)), Phase::Continuous);
+        assert_eq!(warrant.phase(time(17, 30)), Phase::Closed);
+    }
+
+    #[test]
+    fn currencies_close_at_the_roll() {
+        let schedule = Schedule::of(&Kind::parse("Currency", String::new()).unwrap());
+        assert_eq!(schedule.phase(time(0, 0)), Phase::Continuous);
+        assert_eq!(schedule.phase(time(21, 59)), Phase::Continuous);
+        assert_eq!(schedule.phase(time(22, 0)), Phase::Closed);
+        assert_eq!(schedule.phase(time(23, 59)), Phase::Closed);
+    }
+
+    #[test]
+    fn weekends_and_holidays_are_closed() {
+        let mut calendar = Calendar::new(Schedule::of(&equity()));
+        calendar.holidays.insert(NaiveDate::from_ymd_opt(2024, 3, 11).unwrap());
+        assert_eq!(calendar.phase(at("2024-03-08T10:00")), Phase::Continuous);
+        assert_eq!(calendar.phase(at("2024-03-09T10:00")), Phase::Closed);
+        assert_eq!(calendar.phase(at("2024-03-11T10:00")), Phase::Closed);
+        // from a Friday evening, past the weekend and the holiday Monday
+        assert_eq!(calendar.next_change(at("2024-03-08T18:00")), Some((at("2024-03-12T08:00"), Phase::PreOpen)));
+        assert_eq!(calendar.next_change(at("2024-03-12T09:30")), Some((at("2024-03-12T17:30"), Phase::ClosingAuction)));
+    }
+
+    #[test]
+    fn events_wait_for_continuous_trading() {
+        let sessions = Sessions::new(at("2024-03-04T08:45"), &HashMap::new());
+        let kind = equity();
+        let minutes = |m: u64| Duration::from_secs(m * 60);
+        assert_eq!(sessions.phase(&kind, minutes(0)), Phase::PreOpen);
+        assert_eq!(sessions.next_event(&kind, minutes(0), minutes(1)), Some(minutes(5)));
+        assert_eq!(sessions.phase(&kind, minutes(20)), Phase::Continuous);
+        assert_eq!(sessions.next_event(&kind, minutes(20), minutes(21)), Some(minutes(21)));
+        // an update due after the closing auction starts is held at its start
+        let close = minutes(8 * 60 + 45);
+        assert_eq!(sessions.next_event(&kind, close - minutes(1), close + minutes(1)), Some(close));
+    }
+
+    #[test]
+    fn auctions_print_when_trading_starts_or_stops() {
+        assert_eq!(Phase::auction_print(Phase::OpeningAuction, Phase::Continuous), Some(Phase::OpeningAuction));
+        assert_eq!(Phase::auction_print(Phase::ClosingAuction, Phase::Closed), Some(Phase::ClosingAuction));
+        assert_eq!(Phase::auction_print(Phase::Continuous, Phase::Closed), Some(Phase::ClosingAuction));
+        assert_eq!(Phase::auction_print(Phase::Continuous, Phase::ClosingAuction), None);
+        assert_eq!(Phase::auction_print(Phase::Closed, Phase::PreOpen), None);
+    }
+}
@@ -0,0 +1,266 @@
+// Synthetic instruments: prices computed from other instruments of the feed by an expression of their
+// symbols, numbers, + - * / and parentheses, recomputed whenever a constituent updates:
+//   AAPL - MSFT                  a spread
+//   EUR= * CHF=                  the EURCHF cross from EURUSD and USDCHF
+//   0.6 * AAPL + 0.4 * MSFT      a weighted basket
+// A symbol is a run of letters, digits and . = # _ characters, a run that reads as a number being a number.
+// The bid and ask are computed over the bids and asks of the constituents, bounding every price the
+// expression can take, so that a spread buys the ask of one leg and sells the bid of the other.
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Symbol(String),
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Operand(String),
+    Op(char),
+    Open,
+    Close,
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '.' | '=' | '#' | '_')
+}
+
+fn tokens(expression: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' => {}
+            '+' | '-' | '*' | '/' => tokens.push(Token::Op(c)),
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            c if is_symbol_char(c) => {
+                let mut operand = c.to_string();
+                while let Some(c) = chars.next_if(|c| is_symbol_char(*c)) {
+                    operand.push(c);
+                }
+                tokens.push(Token::Operand(operand));
+            }
+            c => return Err(format!("unexpected '{}' in expression '{}'", c, expression))
+        }
+    }
+    Ok(tokens)
+}
+
+// Recursive descent parser over the tokens, `*` and `/` binding tighter than `+` and `-`.
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+}
+
+impl Parser {
+    fn next_if(&mut self, token: &Token) -> bool {
+        let matches = self.tokens.get(self.at) == Some(token);
+        if matches {
+            self.at += 1;
+        }
+        matches
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut expr = self.product()?;
+        loop {
+            let op = if self.next_if(&Token::Op('+')) {
+                Op::Add
+            } else if self.next_if(&Token::Op('-')) {
+                Op::Sub
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut expr = self.factor()?;
+        loop {
+            let op = if self.next_if(&Token::Op('*')) {
+                Op::Mul
+            } else if self.next_if(&Token::Op('/')) {
+                Op::Div
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.factor()?));
+        }
+    }
+
+    fn factor(&mut self) -> Result<Expr, String> {
+        if self.next_if(&Token::Op('-')) {
+            return Ok(Expr::Neg(Box::new(self.factor()?)));
+        }
+        if self.next_if(&Token::Open) {
+            let expr = self.sum()?;
+            return if self.next_if(&Token::Close) { Ok(expr) } else { Err("missing ')'".to_string()) };
+        }
+        match self.tokens.get(self.at).cloned() {
+            Some(Token::Operand(operand)) => {
+                self.at += 1;
+                let number = operand.starts_with(|c: char| c.is_ascii_digit() || c == '.').then(|| operand.parse::<f64>().ok()).flatten();
+                Ok(number.map_or(Expr::Symbol(operand), Expr::Number))
+            }
+            _ => Err("missing symbol or number".to_string())
+        }
+    }
+}
+
+impl FromStr for Expr {
+    type Err = String;
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { tokens: tokens(expression)?, at: 0 };
+        let expr = parser.sum()
+            .and_then(|expr| if parser.at == parser.tokens.len() { Ok(expr) } else { Err("unexpected end".to_string()) })
+            .map_err(|e| format!("{} in expression '{}'", e, expression))?;
+        if expr.symbols().is_empty() {
+            return Err(format!("expression '{}' should use at least one symbol", expression));
+        }
+        Ok(expr)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Number(number) => write!(f, "{}", number),
+            Expr::Symbol(symbol) => write!(f, "{}", symbol),
+            Expr::Neg(expr) => write!(f, "-{}", expr),
+            Expr::Binary(op, left, right) => {
+                let op = match op {
+                    Op::Add => "+",
+                    Op::Sub => "-",
+                    Op::Mul => "*",
+                    Op::Div => "/",
+                };
+                write!(f, "({} {} {})", left, op, right)
+            }
+        }
+    }
+}
+
+impl Expr {
+    // The symbols of the constituents.
+    pub fn symbols(&self) -> BTreeSet<String> {
+        let mut symbols = BTreeSet::new();
+        self.collect(&mut symbols);
+        symbols
+    }
+
+    fn collect(&self, symbols: &mut BTreeSet<String>) {
+        match self {
+            Expr::Number(_) => {}
+            Expr::Symbol(symbol) => {
+                symbols.insert(symbol.to_string());
+            }
+            Expr::Neg(expr) => expr.collect(symbols),
+            Expr::Binary(_, left, right) => {
+                left.collect(symbols);
+                right.collect(symbols);
+            }
+        }
+    }
+
+    // The lowest and highest values of the expression when each symbol takes a value between the bounds
+    // given by `prices`. `None` when a symbol has no price or a divisor may be zero.
+    pub fn bounds(&self, prices: &dyn Fn(&str) -> Option<(f64, f64)>) -> Option<(f64, f64)> {
+        match self {
+            Expr::Number(number) => Some((*number, *number)),
+            Expr::Symbol(symbol) => prices(symbol),
+            Expr::Neg(expr) => expr.bounds(prices).map(|(low, high)| (-high, -low)),
+            Expr::Binary(op, left, right) => {
+                let (a, b) = left.bounds(prices)?;
+                let (c, d) = right.bounds(prices)?;
+                match op {
+                    Op::Add => Some((a + c, b + d)),
+                    Op::Sub => Some((a - d, b - c)),
+                    Op::Mul => Some(extremes([a * c, a * d, b * c, b * d])),
+                    Op::Div if c > 0.0 || d < 0.0 => Some(extremes([a / c, a / d, b / c, b / d])),
+                    Op::Div => None
+                }
+            }
+        }
+    }
+
+    // The value of the expression for the prices of the symbols.
+    pub fn value(&self, prices: &dyn Fn(&str) -> Option<f64>) -> Option<f64> {
+        self.bounds(&|symbol| prices(symbol).map(|price| (price, price))).map(|(value, _)| value)
+    }
+}
+
+fn extremes(values: [f64; 4]) -> (f64, f64) {
+    values.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(low, high), v| (low.min(*v), high.max(*v)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(expression: &str) -> String {
+        expression.parse::<Expr>().unwrap().to_string()
+    }
+
+    #[test]
+    fn products_bind_tighter_than_sums() {
+        assert_eq!(parse("0.6 * AAPL + 0.4 * MSFT"), "((0.6 * AAPL) + (0.4 * MSFT))");
+        assert_eq!(parse("AAPL - MSFT - IBM"), "((AAPL - MSFT) - IBM)");
+        assert_eq!(parse("AAPL / MSFT * 2"), "((AAPL / MSFT) * 2)");
+        assert_eq!(parse("(AAPL + MSFT) * 2"), "((AAPL + MSFT) * 2)");
+        assert_eq!(parse("-EUR= * CHF="), "(-EUR= * CHF=)");
+        assert_eq!(parse("VOD.L - 2"), "(VOD.L - 2)");
+    }
+
+    #[test]
+    fn bad_expressions_are_rejected() {
+        for expression in ["", "AAPL +", "(AAPL", "AAPL)", "AAPL MSFT", "AAPL % 2", "* AAPL", "1 + 2"] {
+            assert!(expression.parse::<Expr>().is_err(), "{} should be rejected", expression);
+        }
+    }
+
+    #[test]
+    fn spread_bounds_buy_one_leg_and_sell_the_other() {
+        let prices = |symbol: &str| match symbol {
+            "AAPL" => Some((10.0, 11.0)),
+            "MSFT" => Some((4.0, 5.0)),
+            _ => None
+        };
+        let spread: Expr = "AAPL - MSFT".parse().unwrap();
+        assert_eq!(spread.bounds(&prices), Some((5.0, 7.0)));
+        let ratio: Expr = "AAPL / MSFT".parse().unwrap();
+        assert_eq!(ratio.bounds(&prices), Some((2.0, 2.75)));
+        let missing: Expr = "AAPL + IBM".parse().unwrap();
+        assert_eq!(missing.bounds(&prices), None);
+    }
+
+    #[test]
+    fn divisor_straddling_zero_has_no_bounds() {
+        let prices = |symbol: &str| match symbol {
+            "AAPL" => Some((10.0, 11.0)),
+            "SPREAD" => Some((-0.5, 0.5)),
+            "FLAT" => Some((0.0, 0.0)),
+            _ => None
+        };
+        for expression in ["AAPL / SPREAD", "AAPL / FLAT", "AAPL / (AAPL - 10.5)"] {
+            let expr: Expr = expression.parse().unwrap();
+            assert_eq!(expr.bounds(&prices), None, "{}", expression);
+        }
+        let negative: Expr = "AAPL / (SPREAD - 1)".parse().unwrap();
+        assert_eq!(negative.bounds(&prices), Some((-22.0, 10.0 / -1.5)));
+    }
+}